@@ -31,15 +31,143 @@ macro_rules! try_or_error {
     };
 }
 
-fn parse_field_name(field: &syn::Field) -> syn::Result<String> {
+#[derive(Debug)]
+struct FieldAttr {
+    name: Option<syn::LitStr>,
+    aliases: Vec<syn::LitStr>,
+}
+
+impl syn::parse::Parse for FieldAttr {
+    fn parse(input: &syn::parse::ParseBuffer<'_>) -> syn::Result<Self> {
+        let name = if input.peek(syn::LitStr) {
+            Some(input.parse()?)
+        } else {
+            None
+        };
+        let mut aliases = Vec::new();
+
+        while !input.is_empty() {
+            if name.is_some() || !aliases.is_empty() {
+                input.parse::<syn::Token![,]>()?;
+
+                if input.is_empty() {
+                    break;
+                }
+            }
+
+            let key: syn::Ident = input.parse()?;
+            if key != "alias" {
+                return Err(syn::Error::new_spanned(key, "expected `alias = \"...\"`"));
+            }
+            input.parse::<syn::Token![=]>()?;
+            aliases.push(input.parse()?);
+        }
+
+        Ok(FieldAttr { name, aliases })
+    }
+}
+
+/// Parse an optional `id_field = "..."` argument, as accepted by `related` and `referenced`.
+fn parse_id_field(input: &syn::parse::ParseBuffer<'_>) -> syn::Result<Option<syn::LitStr>> {
+    if input.is_empty() {
+        return Ok(None);
+    }
+
+    let key: syn::Ident = input.parse()?;
+    if key != "id_field" {
+        return Err(syn::Error::new_spanned(
+            key,
+            "expected `id_field = \"...\"`",
+        ));
+    }
+    input.parse::<syn::Token![=]>()?;
+    let id_field = input.parse()?;
+
+    if !input.is_empty() {
+        input.parse::<syn::Token![,]>()?;
+    }
+
+    Ok(Some(id_field))
+}
+
+/// The arguments of the `related` attribute.
+struct RelatedAttr {
+    related_type: syn::TypePath,
+    id_field: Option<syn::LitStr>,
+}
+
+impl syn::parse::Parse for RelatedAttr {
+    fn parse(input: &syn::parse::ParseBuffer<'_>) -> syn::Result<Self> {
+        let related_type = input.parse()?;
+        if !input.is_empty() {
+            input.parse::<syn::Token![,]>()?;
+        }
+
+        Ok(RelatedAttr {
+            related_type,
+            id_field: parse_id_field(input)?,
+        })
+    }
+}
+
+/// The arguments of the `referenced` attribute.
+struct ReferencedAttr {
+    id_field: Option<syn::LitStr>,
+}
+
+impl syn::parse::Parse for ReferencedAttr {
+    fn parse(input: &syn::parse::ParseBuffer<'_>) -> syn::Result<Self> {
+        Ok(ReferencedAttr {
+            id_field: parse_id_field(input)?,
+        })
+    }
+}
+
+/// The arguments of the `builder` attribute on a field: `default` to fill the field in with
+/// [`Default`], or `default = value` to use a specific value.
+struct BuilderAttr {
+    default: Option<syn::Expr>,
+}
+
+impl syn::parse::Parse for BuilderAttr {
+    fn parse(input: &syn::parse::ParseBuffer<'_>) -> syn::Result<Self> {
+        let key: syn::Ident = input.parse()?;
+        if key != "default" {
+            return Err(syn::Error::new_spanned(
+                key,
+                "expected `default` or `default = ...`",
+            ));
+        }
+
+        let default = if input.peek(syn::Token![=]) {
+            input.parse::<syn::Token![=]>()?;
+            Some(input.parse()?)
+        } else {
+            None
+        };
+
+        Ok(BuilderAttr { default })
+    }
+}
+
+/// Get the property name of a field, along with any aliases it may also be read from.
+fn parse_field_names(field: &syn::Field) -> syn::Result<(String, Vec<String>)> {
     if let Some(attr) = field
         .attrs
         .iter()
         .find(|attr| attr.style == syn::AttrStyle::Outer && attr.path.is_ident("field"))
     {
-        attr.parse_args::<syn::LitStr>().map(|lit| lit.value())
+        let field_attr: FieldAttr = attr.parse_args()?;
+
+        Ok((
+            field_attr
+                .name
+                .map(|lit| lit.value())
+                .unwrap_or_else(|| field.ident.clone().unwrap().to_string()),
+            field_attr.aliases.iter().map(|lit| lit.value()).collect(),
+        ))
     } else {
-        Ok(field.ident.clone().unwrap().to_string())
+        Ok((field.ident.clone().unwrap().to_string(), Vec::new()))
     }
 }
 
@@ -47,84 +175,251 @@ fn parse_field_name(field: &syn::Field) -> syn::Result<String> {
 struct DerivedField {
     ident: proc_macro2::Ident,
     name: String,
+    aliases: Vec<String>,
     accessor: Option<TokenStream2>,
+    drainer: Option<TokenStream2>,
+    validator: TokenStream2,
     converter: TokenStream2,
     inserter: TokenStream2,
+    ref_inserter: TokenStream2,
+    change_inserter: TokenStream2,
     related_impl: Option<TokenStream2>,
+    query_methods: Option<TokenStream2>,
+    unique_property: Option<String>,
+    id_properties: Vec<String>,
+    /// The property the field is stored in and its `PropKind`, if it is stored in one.
+    property: Option<(String, TokenStream2)>,
 }
 
-fn base_accessor(field_name: &String) -> TokenStream2 {
+fn base_accessor(field_name: &str, aliases: &[String]) -> TokenStream2 {
     quote!(
         object
         .get(#field_name)
-        .ok_or(qualia::ConversionError::FieldMissing(#field_name.to_string()))?
+        #(.or_else(|| object.get(#aliases)))*
+        .ok_or_else(|| qualia::ConversionError::FieldMissing(#field_name.to_string()))?
     )
 }
 
-fn number_accessor(field_name: &String) -> TokenStream2 {
-    let base_accessor = base_accessor(field_name);
+fn number_accessor(field_name: &str, aliases: &[String]) -> TokenStream2 {
+    let base_accessor = base_accessor(field_name, aliases);
 
     quote!(
-        #base_accessor
-        .as_number()
-        .ok_or(
-            qualia::ConversionError::FieldWrongType(
-                #field_name.to_string(),
-                "number".to_string(),
-            ),
-        )?
+        {
+            let value = #base_accessor;
+
+            value
+                .as_number()
+                .ok_or_else(|| qualia::ConversionError::FieldWrongType(
+                    #field_name.to_string(),
+                    "number".to_string(),
+                    value.type_name().to_string(),
+                ))?
+        }
     )
 }
 
-fn string_accessor(field_name: &String) -> TokenStream2 {
-    let base_accessor = base_accessor(field_name);
+fn string_accessor(field_name: &str, aliases: &[String]) -> TokenStream2 {
+    let base_accessor = base_accessor(field_name, aliases);
 
     quote!(
-        #base_accessor
-        .as_str()
-        .ok_or(
-            qualia::ConversionError::FieldWrongType(
-                #field_name.to_string(),
-                "string".to_string(),
-            ),
-        )?
-        .clone()
+        {
+            let value = #base_accessor;
+
+            value
+                .as_str()
+                .ok_or_else(|| qualia::ConversionError::FieldWrongType(
+                    #field_name.to_string(),
+                    "string".to_string(),
+                    value.type_name().to_string(),
+                ))?
+                .clone()
+        }
     )
 }
 
-fn object_id_accessor() -> TokenStream2 {
+/// Like [`string_accessor`], but removing the property from a mutable `object` to avoid cloning
+/// the string. A property of the wrong type is left in place.
+fn string_taker(field_name: &str, aliases: &[String]) -> TokenStream2 {
+    quote!(
+        {
+            let key = [#field_name #(, #aliases)*]
+                .into_iter()
+                .find(|key| object.contains_key(*key))
+                .ok_or_else(|| qualia::ConversionError::FieldMissing(#field_name.to_string()))?;
+
+            match object.remove(key) {
+                Some(qualia::PropValue::String(s)) => s,
+                Some(value) => {
+                    let type_name = value.type_name().to_string();
+                    object.insert(key.to_string(), value);
+
+                    return Err(qualia::ConversionError::FieldWrongType(
+                        #field_name.to_string(),
+                        "string".to_string(),
+                        type_name,
+                    ));
+                }
+                None => unreachable!(),
+            }
+        }
+    )
+}
+
+fn enum_accessor(field_name: &str, aliases: &[String], ty: &syn::TypePath) -> TokenStream2 {
+    let base_accessor = base_accessor(field_name, aliases);
+
+    quote!(
+        {
+            let value = #base_accessor;
+
+            value
+                .as_str()
+                .and_then(|s| <#ty as qualia::PropEnum>::from_prop_str(s))
+                .ok_or_else(|| qualia::ConversionError::FieldWrongType(
+                    #field_name.to_string(),
+                    format!("one of {}", <#ty as qualia::PropEnum>::VARIANTS.join(", ")),
+                    value.type_name().to_string(),
+                ))?
+        }
+    )
+}
+
+fn json_accessor(field_name: &str, aliases: &[String], ty: &syn::TypePath) -> TokenStream2 {
+    let base_accessor = base_accessor(field_name, aliases);
+
+    quote!(
+        {
+            let value = #base_accessor;
+
+            value
+                .as_str()
+                .and_then(|s| qualia::serde_json::from_str::<#ty>(s).ok())
+                .ok_or_else(|| qualia::ConversionError::FieldWrongType(
+                    #field_name.to_string(),
+                    "JSON string".to_string(),
+                    value.type_name().to_string(),
+                ))?
+        }
+    )
+}
+
+fn bignum_accessor(field_name: &str, aliases: &[String], ty: &syn::TypePath) -> TokenStream2 {
+    let base_accessor = base_accessor(field_name, aliases);
+
+    quote!(
+        {
+            let value = #base_accessor;
+
+            value
+                .as_str()
+                .and_then(|s| s.parse::<#ty>().ok())
+                .ok_or_else(|| qualia::ConversionError::FieldWrongType(
+                    #field_name.to_string(),
+                    "decimal string".to_string(),
+                    value.type_name().to_string(),
+                ))?
+        }
+    )
+}
+
+fn optional_number_accessor(field_name: &str, aliases: &[String]) -> TokenStream2 {
     quote!(object
-        .get("object_id")
+        .get(#field_name)
+        #(.or_else(|| object.get(#aliases)))*
         .map(
-            |f| f.as_number().ok_or(qualia::ConversionError::FieldWrongType(
-                "object_id".to_string(),
+            |f| f.as_number().ok_or_else(|| qualia::ConversionError::FieldWrongType(
+                #field_name.to_string(),
                 "number".to_string(),
-            ),)
+                f.type_name().to_string(),
+            ))
         )
         .transpose()?)
 }
 
+fn object_id_accessor() -> TokenStream2 {
+    optional_number_accessor("object_id", &[])
+}
+
 #[allow(unused)]
 fn option_i64_path() -> syn::TypePath {
     syn::parse_str("Option<i64>").unwrap()
 }
 
+/// Split a `Box<T>`, `Rc<T>` or `Arc<T>` into the path of the pointer type (without arguments) and
+/// `T`.
+fn peel_pointer(field_type: &syn::TypePath) -> Option<(syn::Path, syn::TypePath)> {
+    let last_segment = field_type.path.segments.last()?;
+
+    if !["Box", "Rc", "Arc"]
+        .iter()
+        .any(|name| last_segment.ident == name)
+    {
+        return None;
+    }
+
+    let inner_type = match &last_segment.arguments {
+        syn::PathArguments::AngleBracketed(arguments) if arguments.args.len() == 1 => {
+            match arguments.args.first() {
+                Some(syn::GenericArgument::Type(syn::Type::Path(inner_type))) => inner_type.clone(),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+
+    let mut pointer_path = field_type.path.clone();
+    pointer_path.segments.last_mut().unwrap().arguments = syn::PathArguments::None;
+
+    Some((pointer_path, inner_type))
+}
+
 enum FieldKind {
     Number,
+    OptionalNumber,
+    Enum(syn::TypePath),
+    Json(syn::TypePath),
+    BigNum(syn::TypePath),
     String,
     Object(syn::TypePath),
     ObjectId,
+    /// A non-optional `i64` marked with `#[object_id]`, for shapes that are only read.
+    ReadOnlyObjectId,
 }
 
 struct ParsedField {
     ident: proc_macro2::Ident,
     name: String,
+    aliases: Vec<String>,
     kind: FieldKind,
     related_type: Option<syn::TypePath>,
+    unique: bool,
+    id_property: Option<String>,
+    /// For object fields wrapped in a `Box`, `Rc` or `Arc`, the path of the pointer type.
+    object_pointer: Option<syn::Path>,
+    /// The type of the field, if it is marked with `#[skip_if_default]`.
+    skip_if_default: Option<syn::TypePath>,
+}
+
+impl ParsedField {
+    /// The property holding the ID of a referenced object, and the aliases it may also be read
+    /// from.
+    fn id_property_names(&self) -> (String, Vec<String>) {
+        match self.id_property {
+            Some(ref id_property) => (id_property.clone(), Vec::new()),
+            None => (
+                format!("{}_id", self.name),
+                self.aliases
+                    .iter()
+                    .map(|alias| format!("{}_id", alias))
+                    .collect(),
+            ),
+        }
+    }
 }
 
 fn parse_fields(
     named_fields: &syn::FieldsNamed,
+    prefix: &str,
 ) -> syn::Result<(Vec<ParsedField>, Option<syn::Ident>)> {
     let mut rest_field_ident = None;
 
@@ -150,37 +445,139 @@ fn parse_fields(
                     };
 
                 let field_ident = field.ident.clone().unwrap();
-                let field_name = parse_field_name(&field)?;
+                let (mut field_name, mut aliases) = if field.attrs.iter().any(|attr| {
+                    attr.style == syn::AttrStyle::Outer && attr.path.is_ident("object_id")
+                }) {
+                    ("object_id".to_string(), Vec::new())
+                } else {
+                    parse_field_names(field)?
+                };
 
-                let related_type = field
+                let related_attr = field
                     .attrs
                     .iter()
                     .find(|attr| {
                         attr.style == syn::AttrStyle::Outer && attr.path.is_ident("related")
                     })
-                    .map(|attr| attr.parse_args::<syn::TypePath>())
+                    .map(|attr| attr.parse_args::<RelatedAttr>())
+                    .transpose()?;
+                let referenced_attr = field
+                    .attrs
+                    .iter()
+                    .find(|attr| {
+                        attr.style == syn::AttrStyle::Outer && attr.path.is_ident("referenced")
+                    })
+                    .map(|attr| {
+                        if attr.tokens.is_empty() {
+                            Ok(ReferencedAttr { id_field: None })
+                        } else {
+                            attr.parse_args::<ReferencedAttr>()
+                        }
+                    })
                     .transpose()?;
 
-                Ok(Some(ParsedField {
-                    name: field_name.clone(),
-                    ident: field_ident,
-                    kind: if field_name == "object_id" {
+                // For a related ID field, `id_field` just renames the property; it is only
+                // kept separate from the field name for referenced objects, below.
+                let mut id_property = referenced_attr
+                    .and_then(|attr| attr.id_field)
+                    .map(|id_field| id_field.value());
+                let related_type = related_attr.map(|attr| {
+                    if let Some(id_field) = attr.id_field {
+                        id_property = Some(id_field.value());
+                    }
+
+                    attr.related_type
+                });
+
+                let mut object_pointer = None;
+                let kind =
+                    if field_name == "object_id" {
                         if *field_type == option_i64_path() {
                             FieldKind::ObjectId
+                        } else if field_type.path.is_ident("i64")
+                            && field.attrs.iter().any(|attr| {
+                                attr.style == syn::AttrStyle::Outer
+                                    && attr.path.is_ident("object_id")
+                            })
+                        {
+                            FieldKind::ReadOnlyObjectId
                         } else {
                             return Err(syn::Error::new_spanned(
                                 &field_type.path,
-                                "object_id field of OptionShape must be Option<i64>",
+                                "object_id field of OptionShape must be Option<i64>, or i64 marked with #[object_id]",
                             ));
                         }
+                    } else if field.attrs.iter().any(|attr| {
+                        attr.style == syn::AttrStyle::Outer && attr.path.is_ident("prop_enum")
+                    }) {
+                        FieldKind::Enum(field_type.clone())
+                    } else if field.attrs.iter().any(|attr| {
+                        attr.style == syn::AttrStyle::Outer && attr.path.is_ident("json")
+                    }) {
+                        FieldKind::Json(field_type.clone())
+                    } else if field.attrs.iter().any(|attr| {
+                        attr.style == syn::AttrStyle::Outer && attr.path.is_ident("bignum")
+                    }) {
+                        FieldKind::BigNum(field_type.clone())
                     } else if field_type.path.is_ident("i64") {
                         FieldKind::Number
+                    } else if *field_type == option_i64_path() {
+                        FieldKind::OptionalNumber
                     } else if field_type.path.is_ident("String") {
                         FieldKind::String
+                    } else if let Some((pointer_path, inner_type)) = peel_pointer(field_type) {
+                        object_pointer = Some(pointer_path);
+                        FieldKind::Object(inner_type)
                     } else {
                         FieldKind::Object(field_type.clone())
-                    },
+                    };
+
+                if !matches!(kind, FieldKind::Object(_)) {
+                    if let Some(id_property) = id_property.take() {
+                        field_name = id_property;
+                    }
+                }
+
+                if !matches!(kind, FieldKind::ObjectId | FieldKind::ReadOnlyObjectId) {
+                    let add_prefix = |name: String| format!("{}{}", prefix, name);
+
+                    field_name = format!("{}{}", prefix, field_name);
+                    aliases = aliases.into_iter().map(add_prefix).collect();
+                    id_property = id_property.map(add_prefix);
+                }
+
+                let skip_if_default = match field.attrs.iter().find(|attr| {
+                    attr.style == syn::AttrStyle::Outer && attr.path.is_ident("skip_if_default")
+                }) {
+                    Some(attr)
+                        if matches!(
+                            kind,
+                            FieldKind::Object(_)
+                                | FieldKind::ObjectId
+                                | FieldKind::ReadOnlyObjectId
+                        ) =>
+                    {
+                        return Err(syn::Error::new_spanned(
+                            attr,
+                            "skip_if_default can't be used on object or object_id fields",
+                        ));
+                    }
+                    Some(_) => Some(field_type.clone()),
+                    None => None,
+                };
+
+                Ok(Some(ParsedField {
+                    name: field_name,
+                    aliases,
+                    ident: field_ident,
+                    kind,
                     related_type,
+                    unique: field.attrs.iter().any(|attr| {
+                        attr.style == syn::AttrStyle::Outer && attr.path.is_ident("unique")
+                    }),
+                    id_property,
+                    object_pointer,
+                    skip_if_default,
                 }))
             })
             .collect::<syn::Result<Vec<_>>>()?
@@ -194,13 +591,28 @@ fn parse_fields(
 fn derive_fields(
     orig_type_name: &syn::Ident,
     named_fields: &syn::FieldsNamed,
+    prefix: &str,
 ) -> syn::Result<(Vec<DerivedField>, TokenStream2, Option<syn::Ident>)> {
-    let (parsed_fields, rest_field_ident) = parse_fields(named_fields)?;
+    let (parsed_fields, rest_field_ident) = parse_fields(named_fields, prefix)?;
     let mut assertions = Vec::new();
 
     let mut prologue = Vec::new();
 
-    if let Some(field) = parsed_fields.iter().find(|f| f.name == "object_id") {
+    if let Some(field) = parsed_fields
+        .iter()
+        .filter(|f| f.name == "object_id")
+        .nth(1)
+    {
+        return Err(syn::Error::new_spanned(
+            &field.ident,
+            "ObjectShape can only have one object_id field",
+        ));
+    }
+
+    if let Some(field) = parsed_fields
+        .iter()
+        .find(|f| matches!(f.kind, FieldKind::ObjectId))
+    {
         let field_ident = field.ident.clone();
 
         prologue.push(quote!(
@@ -221,8 +633,13 @@ fn derive_fields(
         .map(|field| {
             let field_type_converter = match field.kind {
                 FieldKind::ObjectId => object_id_accessor(),
-                FieldKind::Number => number_accessor(&field.name),
-                FieldKind::String => string_accessor(&field.name),
+                FieldKind::ReadOnlyObjectId => number_accessor(&field.name, &[]),
+                FieldKind::Number => number_accessor(&field.name, &field.aliases),
+                FieldKind::OptionalNumber => optional_number_accessor(&field.name, &field.aliases),
+                FieldKind::Enum(ref ty) => enum_accessor(&field.name, &field.aliases, ty),
+                FieldKind::Json(ref ty) => json_accessor(&field.name, &field.aliases, ty),
+                FieldKind::BigNum(ref ty) => bignum_accessor(&field.name, &field.aliases, ty),
+                FieldKind::String => string_accessor(&field.name, &field.aliases),
                 FieldKind::Object(ref ty) => {
                     assertions.push(quote! {
                         || {
@@ -231,25 +648,80 @@ fn derive_fields(
                         };
                     });
 
-                    let id_field_name = format!("{}_id", field.name);
-                    let id_accessor = number_accessor(&id_field_name);
+                    let (id_field_name, id_field_aliases) = field.id_property_names();
+                    let id_accessor = number_accessor(&id_field_name, &id_field_aliases);
+
+                    let field_name = &field.name;
+                    let fetch = quote!(store.query(#ty::q().id(id)).one_as()?);
+                    let fetch = match field.object_pointer {
+                        Some(ref pointer_path) => quote!(#pointer_path::new(#fetch)),
+                        None => fetch,
+                    };
 
                     quote! {
                         {
                             let id = #id_accessor;
-                            store.query(#ty::q().id(id)).one_as()?
+                            if !store.exists_id(id)? {
+                                return Err(qualia::StoreError::RelatedMissing {
+                                    field: #field_name.to_string(),
+                                    id,
+                                });
+                            }
+
+                            #fetch
                         }
                     }
                 }
             };
 
-            let field_type_accessor = match field.kind {
-                FieldKind::ObjectId | FieldKind::Number | FieldKind::String => {
-                    Some(field_type_converter.clone())
+            // Missing properties of fields skipped when they have their default value are read as
+            // that default.
+            let skip_if_default = |converter: TokenStream2| match field.skip_if_default {
+                Some(ref ty) => {
+                    let field_name = &field.name;
+                    let aliases = &field.aliases;
+
+                    quote! {
+                        if object.get(#field_name)#(.or_else(|| object.get(#aliases)))*.is_none() {
+                            <#ty as Default>::default()
+                        } else {
+                            #converter
+                        }
+                    }
                 }
+                None => converter,
+            };
+            let field_type_converter = skip_if_default(field_type_converter);
+
+            let field_type_accessor = match field.kind {
+                FieldKind::ObjectId
+                | FieldKind::ReadOnlyObjectId
+                | FieldKind::Number
+                | FieldKind::OptionalNumber
+                | FieldKind::Enum(_)
+                | FieldKind::Json(_)
+                | FieldKind::BigNum(_)
+                | FieldKind::String => Some(field_type_converter.clone()),
                 FieldKind::Object(_) => None,
             };
 
+            // Like `field_type_accessor`, but moving strings out of a mutable object rather than
+            // cloning them.
+            let field_type_drainer = match field.kind {
+                FieldKind::String => Some(skip_if_default(string_taker(&field.name, &field.aliases))),
+                _ => field_type_accessor.clone(),
+            };
+
+            // Related objects can only be checked for their ID without a store.
+            let field_validator = match field.kind {
+                FieldKind::Object(_) => {
+                    let (id_field_name, id_field_aliases) = field.id_property_names();
+
+                    number_accessor(&id_field_name, &id_field_aliases)
+                }
+                _ => field_type_converter.clone(),
+            };
+
             let field_name = field.name.clone();
             let field_ident = field.ident.clone();
             let field_inserter = match field.kind {
@@ -258,11 +730,36 @@ fn derive_fields(
                         result.insert("object_id".into(), object_id.into());
                     }
                 },
+                FieldKind::ReadOnlyObjectId => quote! {
+                    result.insert("object_id".into(), self.#field_ident.into());
+                },
                 FieldKind::Number | FieldKind::String => quote! {
                     result.insert(#field_name.into(), self.#field_ident.into());
                 },
+                FieldKind::Enum(_) => quote! {
+                    result.insert(
+                        #field_name.into(),
+                        qualia::PropEnum::to_prop_str(&self.#field_ident).into(),
+                    );
+                },
+                FieldKind::Json(_) => quote! {
+                    result.insert(
+                        #field_name.into(),
+                        qualia::serde_json::to_string(&self.#field_ident)
+                            .expect("JSON fields should be serializable")
+                            .into(),
+                    );
+                },
+                FieldKind::BigNum(_) => quote! {
+                    result.insert(#field_name.into(), self.#field_ident.to_string().into());
+                },
+                FieldKind::OptionalNumber => quote! {
+                    if let Some(value) = self.#field_ident {
+                        result.insert(#field_name.into(), value.into());
+                    }
+                },
                 FieldKind::Object(_) => {
-                    let id_field_name = format!("{}_id", field.name);
+                    let (id_field_name, _) = field.id_property_names();
                     quote! {
                         result.insert(
                             #id_field_name.into(),
@@ -272,6 +769,79 @@ fn derive_fields(
                 },
             };
 
+            // Like `field_inserter`, but cloning values that it would move out of `self`.
+            let field_ref_inserter = match field.kind {
+                FieldKind::Number | FieldKind::String => quote! {
+                    result.insert(#field_name.into(), self.#field_ident.clone().into());
+                },
+                _ => field_inserter.clone(),
+            };
+
+            let (field_inserter, field_ref_inserter) = match field.skip_if_default {
+                Some(ref ty) => (
+                    quote! {
+                        if self.#field_ident != <#ty as Default>::default() {
+                            #field_inserter
+                        }
+                    },
+                    quote! {
+                        if self.#field_ident != <#ty as Default>::default() {
+                            #field_ref_inserter
+                        }
+                    },
+                ),
+                None => (field_inserter, field_ref_inserter),
+            };
+
+            // Inserts the value of the field in `other` if it differs from the one in `self`.
+            let field_change_inserter = match field.kind {
+                FieldKind::ObjectId | FieldKind::ReadOnlyObjectId => quote!(),
+                FieldKind::Number => quote! {
+                    if self.#field_ident != other.#field_ident {
+                        result.insert(#field_name.into(), other.#field_ident.into());
+                    }
+                },
+                FieldKind::String => quote! {
+                    if self.#field_ident != other.#field_ident {
+                        result.insert(#field_name.into(), other.#field_ident.clone().into());
+                    }
+                },
+                FieldKind::Enum(_) => quote! {
+                    let value = qualia::PropEnum::to_prop_str(&other.#field_ident);
+                    if qualia::PropEnum::to_prop_str(&self.#field_ident) != value {
+                        result.insert(#field_name.into(), value.into());
+                    }
+                },
+                FieldKind::Json(_) => quote! {
+                    let value = qualia::serde_json::to_string(&other.#field_ident)
+                        .expect("JSON fields should be serializable");
+                    if qualia::serde_json::to_string(&self.#field_ident).ok().as_ref() != Some(&value) {
+                        result.insert(#field_name.into(), value.into());
+                    }
+                },
+                FieldKind::BigNum(_) => quote! {
+                    if self.#field_ident != other.#field_ident {
+                        result.insert(#field_name.into(), other.#field_ident.to_string().into());
+                    }
+                },
+                FieldKind::OptionalNumber => quote! {
+                    if let Some(value) = other.#field_ident {
+                        if self.#field_ident != Some(value) {
+                            result.insert(#field_name.into(), value.into());
+                        }
+                    }
+                },
+                FieldKind::Object(_) => {
+                    let (id_field_name, _) = field.id_property_names();
+                    quote! {
+                        let value = other.#field_ident.get_object_id().unwrap();
+                        if self.#field_ident.get_object_id() != Some(value) {
+                            result.insert(#id_field_name.into(), value.into());
+                        }
+                    }
+                }
+            };
+
             let related_impl = field.related_type.as_ref().map(|related_type| {
         let field_ident = field.ident.clone();
         let helper_base = field
@@ -282,20 +852,132 @@ fn derive_fields(
             .replace("_id", "");
         let fetch_name = format_ident!("fetch_{}", helper_base);
 
-        quote!(
-            fn #fetch_name(&self, store: &qualia::Store) -> qualia::Result<#related_type> where #related_type: qualia::ObjectShapeWithId {
-                store.query(<#related_type as qualia::Queryable>::q().id(self.#field_ident)).one_as()
+        match field.kind {
+            FieldKind::OptionalNumber => quote!(
+                fn #fetch_name(&self, store: &qualia::Store) -> qualia::Result<Option<#related_type>> where #related_type: qualia::ObjectShapeWithId {
+                    self.#field_ident
+                        .map(|id| store.query(<#related_type as qualia::Queryable>::q().id(id)).one_as())
+                        .transpose()
+                }
+            ),
+            _ => quote!(
+                fn #fetch_name(&self, store: &qualia::Store) -> qualia::Result<#related_type> where #related_type: qualia::ObjectShapeWithId {
+                    store.query(<#related_type as qualia::Queryable>::q().id(self.#field_ident)).one_as()
+                }
+            ),
+        }
+            });
+
+            let query_methods = match field.kind {
+                FieldKind::Number => {
+                    let gt_ident = format_ident!("{}_gt", field_ident);
+                    let lt_ident = format_ident!("{}_lt", field_ident);
+                    let between_ident = format_ident!("{}_between", field_ident);
+
+                    Some(quote! {
+                        pub fn #field_ident(self, value: i64) -> Self {
+                            Self(self.0.equal(#field_name, value))
+                        }
+
+                        pub fn #gt_ident(self, value: i64) -> Self {
+                            Self(self.0.greater_than(#field_name, value))
+                        }
+
+                        pub fn #lt_ident(self, value: i64) -> Self {
+                            Self(self.0.less_than(#field_name, value))
+                        }
+
+                        pub fn #between_ident(self, low: i64, high: i64) -> Self {
+                            Self(self.0.between(#field_name, low, high))
+                        }
+                    })
+                }
+                FieldKind::String => {
+                    let like_ident = format_ident!("{}_like", field_ident);
+                    let starts_with_ident = format_ident!("{}_starts_with", field_ident);
+
+                    Some(quote! {
+                        pub fn #field_ident(self, value: impl Into<String>) -> Self {
+                            Self(self.0.equal(#field_name, value.into()))
+                        }
+
+                        pub fn #like_ident(self, pattern: impl Into<String>) -> Self {
+                            Self(self.0.like(#field_name, pattern))
+                        }
+
+                        pub fn #starts_with_ident(self, prefix: impl Into<String>) -> Self {
+                            Self(self.0.starts_with(#field_name, prefix))
+                        }
+                    })
+                }
+                FieldKind::Enum(ref ty) => Some(quote! {
+                    pub fn #field_ident(self, value: #ty) -> Self {
+                        Self(self.0.equal(#field_name, qualia::PropEnum::to_prop_str(&value)))
+                    }
+                }),
+                FieldKind::BigNum(ref ty) => Some(quote! {
+                    pub fn #field_ident(self, value: #ty) -> Self {
+                        Self(self.0.equal(#field_name, value.to_string()))
+                    }
+                }),
+                FieldKind::ObjectId
+                | FieldKind::ReadOnlyObjectId
+                | FieldKind::OptionalNumber
+                | FieldKind::Json(_)
+                | FieldKind::Object(_) => None,
+            };
+
+            let unique_property = field.unique.then(|| match field.kind {
+                FieldKind::Object(_) => field.id_property_names().0,
+                _ => field.name.clone(),
+            });
+
+            let property = match field.kind {
+                FieldKind::ObjectId | FieldKind::ReadOnlyObjectId => None,
+                FieldKind::Number => Some(quote!(qualia::PropKind::Number)),
+                FieldKind::OptionalNumber => Some(quote!(qualia::PropKind::OptionalNumber)),
+                FieldKind::String => Some(quote!(qualia::PropKind::String)),
+                FieldKind::Enum(ref ty) => Some(quote! {
+                    qualia::PropKind::Enum(<#ty as qualia::PropEnum>::VARIANTS)
+                }),
+                FieldKind::Json(_) => Some(quote!(qualia::PropKind::Json)),
+                FieldKind::BigNum(_) => Some(quote!(qualia::PropKind::BigNum)),
+                FieldKind::Object(_) => Some(quote!(qualia::PropKind::Object)),
             }
-        )
+            .map(|kind| match field.kind {
+                FieldKind::Object(_) => (field.id_property_names().0, kind),
+                _ => (field.name.clone(), kind),
             });
 
+            // The properties a referenced object's ID is read from, which are not part of the
+            // rest fields.
+            let id_properties = match field.kind {
+                FieldKind::Object(_) => {
+                    let (id_field_name, id_field_aliases) = field.id_property_names();
+
+                    std::iter::once(id_field_name)
+                        .chain(id_field_aliases)
+                        .collect()
+                }
+                _ => Vec::new(),
+            };
+
             Ok(DerivedField {
                 ident: field_ident,
                 name: field_name,
+                aliases: field.aliases.clone(),
                 accessor: field_type_accessor,
+                drainer: field_type_drainer,
+                validator: field_validator,
                 converter: field_type_converter,
                 inserter: field_inserter,
+                ref_inserter: field_ref_inserter,
+                change_inserter: field_change_inserter,
                 related_impl,
+                query_methods,
+                unique_property,
+                id_properties,
+                property,
             })
         })
         .collect::<syn::Result<Vec<_>>>()?;
@@ -350,8 +1032,8 @@ struct FixedField {
 impl FixedField {
     fn accessor(&self) -> TokenStream2 {
         match self.value {
-            FixedFieldValue::Number(_) => number_accessor(&self.name.value()),
-            FixedFieldValue::String(_) => string_accessor(&self.name.value()),
+            FixedFieldValue::Number(_) => number_accessor(&self.name.value(), &[]),
+            FixedFieldValue::String(_) => string_accessor(&self.name.value(), &[]),
         }
     }
 }
@@ -379,6 +1061,15 @@ impl syn::parse::Parse for FixedFields {
     }
 }
 
+fn parse_prefix(attrs: &[syn::Attribute]) -> syn::Result<String> {
+    attrs
+        .iter()
+        .find(|attr| attr.style == syn::AttrStyle::Outer && attr.path.is_ident("prefix"))
+        .map_or(Ok(String::new()), |attr| {
+            Ok(attr.parse_args::<syn::LitStr>()?.value())
+        })
+}
+
 fn parse_fixed_fields(attrs: &Vec<syn::Attribute>) -> syn::Result<Vec<FixedField>> {
     let attr = match attrs
         .iter()
@@ -400,16 +1091,324 @@ fn parse_fixed_fields(attrs: &Vec<syn::Attribute>) -> syn::Result<Vec<FixedField
         .collect())
 }
 
-/// Automatically translate between properties of Qualia objects and fields of structs.
-///
-/// # Basic example
-///
-/// ```
-/// # use qualia::{object, Object};
-/// # use qualia_derive::ObjectShape;
-/// # use std::convert::{Infallible, TryFrom};
-/// #[derive(Debug, ObjectShape, PartialEq)]
-/// struct CustomShape {
+fn is_option_type(ty: &syn::Type) -> bool {
+    match ty {
+        syn::Type::Path(p) => p
+            .path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "Option"),
+        _ => false,
+    }
+}
+
+/// Check that no field has a `builder` attribute, for shapes without a builder.
+fn check_no_builder_fields(named_fields: &syn::FieldsNamed) -> syn::Result<()> {
+    for field in named_fields.named.iter() {
+        if let Some(attr) = field
+            .attrs
+            .iter()
+            .find(|attr| attr.style == syn::AttrStyle::Outer && attr.path.is_ident("builder"))
+        {
+            return Err(syn::Error::new_spanned(
+                attr,
+                "builder attributes on fields need #[builder] on the struct",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn builder_impl(
+    orig_type_name: &syn::Ident,
+    orig_type_vis: &syn::Visibility,
+    named_fields: &syn::FieldsNamed,
+) -> syn::Result<TokenStream2> {
+    let builder_type_name = format_ident!("{}Builder", orig_type_name);
+
+    let mut field_idents = Vec::new();
+    let mut field_types = Vec::new();
+    let mut field_fillers = Vec::new();
+
+    for field in named_fields.named.iter() {
+        let field_ident = field.ident.clone().unwrap();
+        let find_attr = |name| {
+            field
+                .attrs
+                .iter()
+                .find(|attr| attr.style == syn::AttrStyle::Outer && attr.path.is_ident(name))
+        };
+
+        let builder_attr = find_attr("builder")
+            .map(|attr| attr.parse_args::<BuilderAttr>())
+            .transpose()?;
+
+        let filler = if let Some(builder_attr) = builder_attr {
+            match builder_attr.default {
+                Some(default) => quote!(self.#field_ident.unwrap_or_else(|| (#default).into())),
+                None => quote!(self.#field_ident.unwrap_or_default()),
+            }
+        } else if find_attr("rest_fields").is_some()
+            || find_attr("skip_if_default").is_some()
+            || is_option_type(&field.ty)
+        {
+            quote!(self.#field_ident.unwrap_or_default())
+        } else {
+            let related_id_field = find_attr("related")
+                .map(|attr| attr.parse_args::<RelatedAttr>())
+                .transpose()?
+                .and_then(|attr| attr.id_field);
+
+            let field_name = if find_attr("object_id").is_some() {
+                "object_id".to_string()
+            } else if let Some(id_field) = related_id_field {
+                id_field.value()
+            } else {
+                parse_field_names(field)?.0
+            };
+
+            quote!(
+                self.#field_ident.ok_or_else(
+                    || qualia::ConversionError::FieldMissing(#field_name.to_string())
+                )?
+            )
+        };
+
+        field_idents.push(field_ident);
+        field_types.push(field.ty.clone());
+        field_fillers.push(filler);
+    }
+
+    Ok(quote! {
+        /// A builder for
+        #[doc = concat!("[`", stringify!(#orig_type_name), "`].")]
+        #[allow(dead_code)]
+        #orig_type_vis struct #builder_type_name {
+            #(#field_idents: Option<#field_types>),*
+        }
+
+        #[allow(dead_code)]
+        impl #builder_type_name {
+            #(
+                pub fn #field_idents(mut self, value: impl Into<#field_types>) -> Self {
+                    self.#field_idents = Some(value.into());
+                    self
+                }
+            )*
+
+            /// Build the shape, filling in defaults for any fields that were not set.
+            ///
+            /// Will return [`ConversionError::FieldMissing`](qualia::ConversionError::FieldMissing)
+            /// if a field without a default was not set.
+            pub fn build(self) -> std::result::Result<#orig_type_name, qualia::ConversionError> {
+                Ok(#orig_type_name {
+                    #(#field_idents: #field_fillers),*
+                })
+            }
+        }
+
+        impl #orig_type_name {
+            /// Get a builder for this shape.
+            #[allow(dead_code)]
+            #orig_type_vis fn builder() -> #builder_type_name {
+                #builder_type_name {
+                    #(#field_idents: None),*
+                }
+            }
+        }
+    })
+}
+
+fn queryable_impl(
+    orig_type_name: &syn::Ident,
+    fixed_field_names: &[syn::LitStr],
+    fixed_field_values: &[TokenStream2],
+) -> TokenStream2 {
+    quote!(
+        impl qualia::Queryable for #orig_type_name {
+            fn q() -> qualia::query_builder::QueryBuilder {
+                qualia::Q
+                #(
+                    .equal(
+                        #fixed_field_names,
+                        #fixed_field_values,
+                    )
+                )*
+            }
+        }
+    )
+}
+
+/// Generate only the [`Queryable`](qualia::Queryable) query helper for a type.
+///
+/// This is useful for types that are only used to scope queries, and never converted to or from
+/// objects. The query is built from the `fixed_fields` attribute, as with
+/// [`ObjectShape`](macro@ObjectShape). Don't derive both on the same type; `ObjectShape` already
+/// implements [`Queryable`](qualia::Queryable).
+///
+/// ```
+/// # use qualia::{Queryable, Q};
+/// #[derive(Queryable)]
+/// #[fixed_fields("kind" => "file")]
+/// struct Files;
+///
+/// assert_eq!(Files::q().build(), Q.equal("kind", "file").build());
+/// ```
+#[proc_macro_derive(Queryable, attributes(fixed_fields))]
+pub fn derive_queryable(input: TokenStream) -> TokenStream {
+    let parsed_type = parse_macro_input!(input as DeriveInput);
+
+    let fixed_fields = try_or_error!(parse_fixed_fields(&parsed_type.attrs));
+
+    let fixed_field_names: Vec<_> = fixed_fields.iter().map(|f| f.name.clone()).collect();
+    let fixed_field_values: Vec<_> = fixed_fields
+        .iter()
+        .map(|f| f.value.to_token_stream())
+        .collect();
+
+    queryable_impl(&parsed_type.ident, &fixed_field_names, &fixed_field_values).into()
+}
+
+fn parse_rename_all(attrs: &[syn::Attribute]) -> syn::Result<Option<Case>> {
+    let attr = match attrs
+        .iter()
+        .find(|attr| attr.style == syn::AttrStyle::Outer && attr.path.is_ident("prop_enum"))
+    {
+        Some(a) => a,
+        None => return Ok(None),
+    };
+
+    let name_value: syn::MetaNameValue = attr.parse_args()?;
+    if !name_value.path.is_ident("rename_all") {
+        return Err(syn::Error::new_spanned(
+            &name_value.path,
+            "expected `rename_all = \"...\"`",
+        ));
+    }
+
+    let case = match &name_value.lit {
+        syn::Lit::Str(lit) => match lit.value().as_str() {
+            "lowercase" => Case::Flat,
+            "UPPERCASE" => Case::UpperFlat,
+            "snake_case" => Case::Snake,
+            "SCREAMING_SNAKE_CASE" => Case::UpperSnake,
+            "kebab-case" => Case::Kebab,
+            "camelCase" => Case::Camel,
+            "PascalCase" => Case::Pascal,
+            _ => return Err(syn::Error::new_spanned(lit, "unknown case for rename_all")),
+        },
+        lit => return Err(syn::Error::new_spanned(lit, "rename_all must be a string")),
+    };
+
+    Ok(Some(case))
+}
+
+/// Store a field-less enum as a string property.
+///
+/// Each variant is stored as its name, optionally converted to another case with the
+/// `prop_enum(rename_all = "...")` attribute. The supported cases are `lowercase`, `UPPERCASE`,
+/// `snake_case`, `SCREAMING_SNAKE_CASE`, `kebab-case`, `camelCase` and `PascalCase`.
+///
+/// To use the enum as a field of an [`ObjectShape`](macro@ObjectShape), mark the field with
+/// `#[prop_enum]`. Converting an object with a value that isn't one of the variants will fail.
+///
+/// ```
+/// # use qualia::{object, ConversionError, Object, PropEnum};
+/// # use qualia_derive::ObjectShape;
+/// # use std::convert::TryFrom;
+/// #[derive(Clone, Copy, Debug, PartialEq, PropEnum)]
+/// #[prop_enum(rename_all = "lowercase")]
+/// enum Status {
+///     Active,
+///     Archived,
+/// }
+///
+/// #[derive(Debug, ObjectShape, PartialEq)]
+/// struct Project {
+///     name: String,
+///     #[prop_enum]
+///     status: Status,
+/// }
+///
+/// assert_eq!(
+///     Project::try_from(object!("name" => "qualia", "status" => "archived")),
+///     Ok(Project {
+///         name: "qualia".to_string(),
+///         status: Status::Archived,
+///     }),
+/// );
+/// assert!(Project::try_from(object!("name" => "qualia", "status" => "lost")).is_err());
+/// ```
+#[proc_macro_derive(PropEnum, attributes(prop_enum))]
+pub fn derive_prop_enum(input: TokenStream) -> TokenStream {
+    let parsed_type = parse_macro_input!(input as DeriveInput);
+    let type_name = parsed_type.ident;
+
+    let enum_data = try_or_context!(
+        match parsed_type.data {
+            syn::Data::Enum(ref e) => Ok(e),
+            syn::Data::Struct(ref s) => Err(s.struct_token.span),
+            syn::Data::Union(ref u) => Err(u.union_token.span),
+        },
+        "Can only derive PropEnum from an enum",
+    );
+
+    let rename_all = try_or_error!(parse_rename_all(&parsed_type.attrs));
+
+    let mut variant_idents = Vec::new();
+    let mut variant_names = Vec::new();
+
+    for variant in enum_data.variants.iter() {
+        if !matches!(variant.fields, syn::Fields::Unit) {
+            return error_stream!(variant.ident.span(), "PropEnum variants cannot have fields");
+        }
+
+        let name = variant.ident.to_string();
+        variant_names.push(match rename_all {
+            Some(case) => name.to_case(case),
+            None => name,
+        });
+        variant_idents.push(variant.ident.clone());
+    }
+
+    quote!(
+        impl qualia::PropEnum for #type_name {
+            const VARIANTS: &'static [&'static str] = &[#(#variant_names),*];
+
+            fn from_prop_str(s: &str) -> Option<Self> {
+                match s {
+                    #(#variant_names => Some(#type_name::#variant_idents),)*
+                    _ => None,
+                }
+            }
+
+            fn to_prop_str(&self) -> &'static str {
+                match self {
+                    #(#type_name::#variant_idents => #variant_names,)*
+                }
+            }
+        }
+
+        impl std::convert::From<#type_name> for qualia::PropValue {
+            fn from(value: #type_name) -> qualia::PropValue {
+                qualia::PropValue::String(qualia::PropEnum::to_prop_str(&value).to_string())
+            }
+        }
+    )
+    .into()
+}
+
+/// Automatically translate between properties of Qualia objects and fields of structs.
+///
+/// # Basic example
+///
+/// ```
+/// # use qualia::{object, Object};
+/// # use qualia_derive::ObjectShape;
+/// # use std::convert::{Infallible, TryFrom};
+/// #[derive(Debug, ObjectShape, PartialEq)]
+/// struct CustomShape {
 ///     name: String,
 ///     width: i64,
 /// }
@@ -439,6 +1438,9 @@ fn parse_fixed_fields(attrs: &Vec<syn::Attribute>) -> syn::Result<Vec<FixedField
 /// );
 /// ```
 ///
+/// The generated `into_object()` and `to_object()` convert to an [`Object`](qualia::Object)
+/// without a type annotation, the latter leaving the shape in place.
+///
 /// # Renaming properties
 ///
 /// By default, properties get the same name as the field in the struct. This can be changed with
@@ -467,6 +1469,48 @@ fn parse_fixed_fields(attrs: &Vec<syn::Attribute>) -> syn::Result<Vec<FixedField
 /// );
 /// ```
 ///
+/// When a property is renamed, objects stored under the old name can still be read by listing it
+/// as an `alias`. Aliases are only used when the property itself is missing, and objects are always
+/// written with the new name:
+///
+/// ```
+/// # use qualia::{object, Object};
+/// # use qualia_derive::ObjectShape;
+/// # use std::convert::TryFrom;
+/// #[derive(Debug, ObjectShape, PartialEq)]
+/// struct CustomShape {
+///     #[field("shape-name", alias = "my-name")]
+///     name: String,
+/// }
+///
+/// let shape = CustomShape::try_from(object!("my-name" => "letter")).unwrap();
+/// let obj: Object = shape.into();
+///
+/// assert_eq!(obj, object!("shape-name" => "letter"));
+/// ```
+///
+/// A prefix can be added to the properties of all fields at once with the `prefix` attribute on
+/// the struct. It is added to renamed properties and aliases as well, but not to `object_id` or
+/// fixed properties:
+///
+/// ```
+/// # use qualia::{object, Object};
+/// # use qualia_derive::ObjectShape;
+/// # use std::convert::TryFrom;
+/// #[derive(Debug, ObjectShape, PartialEq)]
+/// #[prefix("photo.")]
+/// struct Photo {
+///     width: i64,
+///     #[field("tall")]
+///     height: i64,
+/// }
+///
+/// let obj: Object = Photo { width: 4, height: 3 }.into();
+///
+/// assert_eq!(obj, object!("photo.width" => 4, "photo.tall" => 3));
+/// assert_eq!(Photo::WIDTH_FIELD, "photo.width");
+/// ```
+///
 /// # Adding fixed properties
 ///
 /// Additional fields with fixed values can be added with the `fixed_fields` attribute on
@@ -527,6 +1571,155 @@ fn parse_fixed_fields(attrs: &Vec<syn::Attribute>) -> syn::Result<Vec<FixedField
 /// );
 /// ```
 ///
+/// Without such a field, unlisted properties are dropped when an object is converted to the shape
+/// and back. The generated `is_lossless()` returns whether the shape has a `rest_fields` field, and
+/// `would_lose(&object)` lists the properties of an object that would be dropped.
+///
+/// # Converting without cloning
+///
+/// Shapes without referenced objects can be converted from an owned [`Object`](qualia::Object),
+/// a borrowed one, or a mutable one. Converting from a mutable object moves the strings of
+/// `String` fields out of it, rather than cloning them, and removes their properties. Properties
+/// of other fields are left in place, unless there is a `#[rest_fields]` field, which takes all
+/// properties that remain. If the conversion fails, properties removed before the failure stay
+/// removed.
+///
+/// ```
+/// # use qualia::{object, Object};
+/// # use qualia_derive::ObjectShape;
+/// # use std::convert::TryFrom;
+/// #[derive(Debug, ObjectShape, PartialEq)]
+/// struct Note {
+///     title: String,
+///     pages: i64,
+/// }
+///
+/// let mut object = object!("title" => "Minutes", "pages" => 2, "author" => "pat");
+/// let note = Note::try_from(&mut object).unwrap();
+///
+/// assert_eq!(note, Note { title: "Minutes".to_string(), pages: 2 });
+/// assert_eq!(object, object!("pages" => 2, "author" => "pat"));
+/// ```
+///
+/// # Storing structured values
+///
+/// A field of any type that implements `serde::Serialize` and `serde::Deserialize` can be
+/// stored as a property containing a JSON string by marking it with `#[json]`. Converting an
+/// object fails if the property isn't a string containing valid JSON for the field's type, and
+/// converting to an object panics if the field can't be serialized.
+///
+/// ```
+/// # use qualia::{object, Object};
+/// # use qualia_derive::ObjectShape;
+/// # use std::convert::TryFrom;
+/// #[derive(Debug, ObjectShape, PartialEq)]
+/// struct Article {
+///     title: String,
+///     #[json]
+///     tags: Vec<String>,
+/// }
+///
+/// let article = Article::try_from(object!("title" => "Hi", "tags" => r#"["a","b"]"#)).unwrap();
+/// assert_eq!(article.tags, vec!["a".to_string(), "b".to_string()]);
+/// ```
+///
+/// Properties can only hold numbers that fit in an `i64`. Integers of other types, like `u64` or
+/// `i128`, can be stored as a property containing a decimal string by marking the field with
+/// `#[bignum]`; any type that implements `FromStr` and `Display` works. Converting an object
+/// fails if the property isn't a string that parses as the field's type, including when the value
+/// is out of range. Note that these properties compare as strings in queries, so only equality is
+/// meaningful.
+///
+/// ```
+/// # use qualia::{object, Object};
+/// # use qualia_derive::ObjectShape;
+/// # use std::convert::TryFrom;
+/// #[derive(Debug, ObjectShape, PartialEq)]
+/// struct Device {
+///     #[bignum]
+///     serial: u64,
+/// }
+///
+/// let device = Device::try_from(object!("serial" => "18446744073709551615")).unwrap();
+/// assert_eq!(device.serial, u64::MAX);
+/// ```
+///
+/// # Omitting default values
+///
+/// Fields marked with `#[skip_if_default]` are left out of the object when they equal their
+/// type's `Default`, and are set to that default when the property is missing. The field's type
+/// must implement `Default` and `PartialEq`. Queries for the default value won't match objects
+/// where it was left out.
+///
+/// ```
+/// # use qualia::{object, Object};
+/// # use qualia_derive::ObjectShape;
+/// # use std::convert::TryFrom;
+/// #[derive(Debug, ObjectShape, PartialEq)]
+/// struct Task {
+///     name: String,
+///     #[skip_if_default]
+///     notes: String,
+/// }
+///
+/// let object: Object = Task { name: "Write docs".to_string(), notes: String::new() }.into();
+/// assert_eq!(object, object!("name" => "Write docs"));
+/// assert_eq!(
+///     Task::try_from(object),
+///     Ok(Task { name: "Write docs".to_string(), notes: String::new() }),
+/// );
+/// ```
+///
+/// # Unique fields
+///
+/// Fields marked with `#[unique]` are listed in
+/// [`UNIQUE_FIELDS`](qualia::ObjectShape::UNIQUE_FIELDS).
+/// [`Checkpoint::add_with_id()`](qualia::Checkpoint::add_with_id) will refuse to add an object
+/// with the same value for one of these fields as an existing object of the same shape, returning
+/// [`StoreError::Duplicate`](qualia::StoreError::Duplicate) instead.
+///
+/// ```
+/// # use qualia_derive::ObjectShape;
+/// #[derive(Clone, Debug, ObjectShape, PartialEq)]
+/// #[fixed_fields("kind" => "file")]
+/// struct File {
+///     object_id: Option<i64>,
+///     #[unique]
+///     hash: String,
+///     name: String,
+/// }
+///
+/// assert_eq!(<File as qualia::ObjectShape>::UNIQUE_FIELDS, &["hash"]);
+/// ```
+///
+/// # Property kinds
+///
+/// The properties stored for each field, and the kind of value each holds, are listed in
+/// [`PROPERTIES`](qualia::ObjectShape::PROPERTIES). This is enough to build a form for editing
+/// the shape, or to check an object before converting it.
+///
+/// ```
+/// # use qualia::PropKind;
+/// # use qualia_derive::ObjectShape;
+/// #[derive(Clone, Debug, ObjectShape, PartialEq)]
+/// #[fixed_fields("kind" => "file")]
+/// struct File {
+///     object_id: Option<i64>,
+///     name: String,
+///     size: i64,
+///     mode: Option<i64>,
+/// }
+///
+/// assert_eq!(
+///     <File as qualia::ObjectShape>::PROPERTIES,
+///     &[
+///         ("name", PropKind::String),
+///         ("size", PropKind::Number),
+///         ("mode", PropKind::OptionalNumber),
+///     ],
+/// );
+/// ```
+///
 /// # Getting ID of inserted object
 ///
 /// The ID of the object can be retrieved from an `Option<i64>` field named `object_id`:
@@ -556,6 +1749,51 @@ fn parse_fixed_fields(attrs: &Vec<syn::Attribute>) -> syn::Result<Vec<FixedField
 /// // assert!(shape2.object_id.is_some());
 /// ```
 ///
+/// A field with another name can be used for the ID with the `object_id` attribute:
+///
+/// ```
+/// # use qualia::{object, Object, ObjectShapeWithId};
+/// # use qualia_derive::ObjectShape;
+/// # use std::convert::{Infallible, TryFrom, TryInto};
+/// #[derive(Debug, ObjectShape, PartialEq)]
+/// struct CustomShape {
+///     #[object_id]
+///     id: Option<i64>,
+///     width: i64,
+/// }
+///
+/// let shape: CustomShape = object!(
+///     "object_id" => 42,
+///     "width" => 65,
+/// ).try_into().unwrap();
+///
+/// assert_eq!(shape.get_object_id(), Some(42));
+/// ```
+///
+/// Shapes that are only ever read from the store can use a plain `i64` for the ID instead, as long
+/// as it is marked with `#[object_id]`. Converting an object without an `object_id` to such a
+/// shape fails, and the shape does not implement
+/// [`ObjectShapeWithId`](qualia::ObjectShapeWithId), so it can't be used with
+/// [`Checkpoint::add_with_id()`](qualia::Checkpoint::add_with_id) or as a related object.
+///
+/// ```
+/// # use qualia::{object, ConversionError, Object};
+/// # use qualia_derive::ObjectShape;
+/// # use std::convert::TryInto;
+/// #[derive(Debug, ObjectShape, PartialEq)]
+/// struct Listing {
+///     #[object_id]
+///     id: i64,
+///     name: String,
+/// }
+///
+/// let listing: Listing = object!("object_id" => 42, "name" => "notes").try_into().unwrap();
+/// assert_eq!(listing.id, 42);
+///
+/// let missing: Result<Listing, _> = object!("name" => "notes").try_into();
+/// assert_eq!(missing, Err(ConversionError::FieldMissing("object_id".to_string())));
+/// ```
+///
 /// # Accessing related objects
 ///
 /// Often, objects contain references to other object's ID fields. If those objects have a defined
@@ -579,18 +1817,178 @@ fn parse_fixed_fields(attrs: &Vec<syn::Attribute>) -> syn::Result<Vec<FixedField
 ///     width: i64,
 /// }
 ///
-/// // if let Some(group) = custom_shape.fetch_shape_group(&store)? {;
+/// // let group = custom_shape.fetch_shape_group(&store)?;
+/// ```
+///
+/// If the ID field is an `Option<i64>`, the property may be missing, and the helper will return
+/// `None` without fetching anything when it is:
+///
+/// ```
+/// # use qualia_derive::ObjectShape;
+/// # #[derive(Debug, ObjectShape, PartialEq)]
+/// # struct ShapeGroup {
+/// #     object_id: Option<i64>,
+/// #     name: String,
+/// # }
+/// #[derive(Debug, ObjectShape, PartialEq)]
+/// struct CustomShape {
+///     #[related(ShapeGroup)]
+///     shape_group_id: Option<i64>,
+///     width: i64,
+/// }
+///
+/// // if let Some(group) = custom_shape.fetch_shape_group(&store)? {
 /// //     ...
+/// // }
+/// ```
+///
+/// Fields holding another object shape are stored as the ID of that object, in a property named
+/// after the field with `_id` appended. For both these and related ID fields, another property
+/// can be used with `id_field`:
+///
+/// ```
+/// # use qualia::{ObjectShapeWithId, Queryable};
+/// # use qualia_derive::ObjectShape;
+/// # #[derive(Debug, ObjectShape, PartialEq)]
+/// # struct ShapeGroup {
+/// #     object_id: Option<i64>,
+/// #     name: String,
+/// # }
+/// #[derive(Debug, ObjectShape, PartialEq)]
+/// struct CustomShape {
+///     #[related(ShapeGroup, id_field = "group")]
+///     group_id: i64,
+///     #[referenced(id_field = "parent_group")]
+///     parent: ShapeGroup,
+/// }
+/// ```
+///
+/// Object fields may also be wrapped in a `Box`, `Rc` or `Arc`, and the fetched object is wrapped
+/// to match:
+///
+/// ```
+/// # use qualia::{ObjectShapeWithId, Queryable};
+/// # use qualia_derive::ObjectShape;
+/// # use std::rc::Rc;
+/// # #[derive(Debug, ObjectShape, PartialEq)]
+/// # struct ShapeGroup {
+/// #     object_id: Option<i64>,
+/// #     name: String,
+/// # }
+/// #[derive(Debug, ObjectShape, PartialEq)]
+/// struct CustomShape {
+///     parent: Box<ShapeGroup>,
+///     shared_group: Rc<ShapeGroup>,
+/// }
+/// ```
+///
+/// # Typed queries
+///
+/// Marking a shape with `#[typed_query]` generates a typed query builder for it, named after the
+/// shape with a `Query` suffix and available through `query()`. It starts from the same query as
+/// [`q()`](`qualia::Queryable::q()`), and has methods for each number and string field:
+///
+/// * Number fields: `width(value)`, `width_gt(value)`, `width_lt(value)` and
+///   `width_between(low, high)`.
+/// * String fields: `name(value)`, `name_like(pattern)` and `name_starts_with(prefix)`.
+///
+/// ```
+/// # use qualia::Q;
+/// # use qualia_derive::ObjectShape;
+/// #[derive(Debug, ObjectShape, PartialEq)]
+/// #[fixed_fields("kind" => "custom")]
+/// #[typed_query]
+/// struct CustomShape {
+///     name: String,
+///     width: i64,
+/// }
+///
+/// assert_eq!(
+///     CustomShape::query().name_starts_with("let").width_gt(8).build(),
+///     Q.equal("kind", "custom")
+///         .starts_with("name", "let")
+///         .greater_than("width", 8)
+///         .build(),
+/// );
+/// ```
+///
+/// # Builders
+///
+/// Marking a shape with `#[builder]` generates a builder for it, named after the shape with a
+/// `Builder` suffix and available through `builder()`, with a setter for each field. Fields that
+/// are not set are filled in by `build()` from the field's `builder` attribute: either
+/// `#[builder(default)]` to use [`Default`], or `#[builder(default = value)]` to use a specific
+/// value. `Option` and `rest_fields` fields default to empty. Any other field that is not set
+/// causes `build()` to return [`ConversionError::FieldMissing`](qualia::ConversionError::FieldMissing).
+///
+/// ```
+/// # use qualia_derive::ObjectShape;
+/// #[derive(Debug, ObjectShape, PartialEq)]
+/// #[builder]
+/// struct CustomShape {
+///     object_id: Option<i64>,
+///     name: String,
+///     #[builder(default = 8)]
+///     width: i64,
+///     #[builder(default)]
+///     height: i64,
+/// }
+///
+/// assert_eq!(
+///     CustomShape::builder().name("letter").build(),
+///     Ok(CustomShape {
+///         object_id: None,
+///         name: "letter".to_string(),
+///         width: 8,
+///         height: 0,
+///     })
+/// );
+/// ```
+///
+/// # Property name constants
+///
+/// A constant is generated for the property name of each field, named after the field in
+/// `UPPER_SNAKE_CASE` with a `_FIELD` suffix. Using these instead of string literals means renaming
+/// a field causes a compile error rather than a silently broken query:
+///
+/// ```
+/// # use qualia::Q;
+/// # use qualia_derive::ObjectShape;
+/// #[derive(Debug, ObjectShape, PartialEq)]
+/// struct CustomShape {
+///     #[field("shape-width")]
+///     width: i64,
+/// }
+///
+/// assert_eq!(CustomShape::WIDTH_FIELD, "shape-width");
+/// let query = Q.equal(CustomShape::WIDTH_FIELD, 8);
 /// ```
 #[proc_macro_derive(
     ObjectShape,
-    attributes(field, fixed_fields, rest_fields, related, referenced)
+    attributes(
+        field,
+        fixed_fields,
+        rest_fields,
+        related,
+        referenced,
+        object_id,
+        builder,
+        typed_query,
+        prop_enum,
+        json,
+        bignum,
+        skip_if_default,
+        unique,
+        prefix
+    )
 )]
 pub fn derive_object_shape(input: TokenStream) -> TokenStream {
     let parsed_struct = parse_macro_input!(input as DeriveInput);
     let orig_type_name = parsed_struct.ident;
+    let orig_type_vis = parsed_struct.vis;
 
     let fixed_fields = try_or_error!(parse_fixed_fields(&parsed_struct.attrs));
+    let prefix = try_or_error!(parse_prefix(&parsed_struct.attrs));
 
     let mut fixed_field_names = Vec::new();
     let mut fixed_field_values = Vec::new();
@@ -602,6 +2000,8 @@ pub fn derive_object_shape(input: TokenStream) -> TokenStream {
         fixed_field_accessors.push(f.accessor());
     }
 
+    let queryable_impl = queryable_impl(&orig_type_name, &fixed_field_names, &fixed_field_values);
+
     let struct_data = try_or_context!(
         match parsed_struct.data {
             syn::Data::Struct(s) => Ok(s),
@@ -621,24 +2021,68 @@ pub fn derive_object_shape(input: TokenStream) -> TokenStream {
     );
 
     let (derived_fields, prologue, rest_field_ident) =
-        try_or_error!(derive_fields(&orig_type_name, &named_fields));
+        try_or_error!(derive_fields(&orig_type_name, named_fields, &prefix));
+    let builder_impl = if parsed_struct
+        .attrs
+        .iter()
+        .any(|attr| attr.style == syn::AttrStyle::Outer && attr.path.is_ident("builder"))
+    {
+        try_or_error!(builder_impl(&orig_type_name, &orig_type_vis, named_fields))
+    } else {
+        try_or_error!(check_no_builder_fields(named_fields));
+        quote!()
+    };
 
     let mut field_names = Vec::new();
     let mut field_idents = Vec::new();
     let mut field_accessors = Vec::new();
+    let mut field_drainers = Vec::new();
+    let mut field_validators = Vec::new();
     let mut field_inserters = Vec::new();
+    let mut field_ref_inserters = Vec::new();
+    let mut field_change_inserters = Vec::new();
     let mut field_converters = Vec::new();
     let mut field_related_impls = Vec::new();
+    let mut field_query_methods = Vec::new();
+    let mut field_name_consts = Vec::new();
     let mut has_full_accessor_impl = true;
+    let unique_properties: Vec<_> = derived_fields
+        .iter()
+        .filter_map(|f| f.unique_property.clone())
+        .collect();
+    let (property_names, property_kinds): (Vec<_>, Vec<_>) = derived_fields
+        .iter()
+        .filter_map(|f| f.property.clone())
+        .unzip();
+    let uses_object_id = rest_field_ident.is_some()
+        || derived_fields
+            .iter()
+            .any(|f| f.name == "object_id" || f.aliases.iter().any(|a| a == "object_id"));
 
     for f in derived_fields.into_iter() {
+        let const_ident = format_ident!("{}_FIELD", f.ident.to_string().to_case(Case::UpperSnake));
+        let field_ident = &f.ident;
+        let field_name = &f.name;
+        field_name_consts.push(quote!(
+            #[doc = concat!("The name of the property for the `", stringify!(#field_ident), "` field.")]
+            #[allow(dead_code)]
+            pub const #const_ident: &'static str = #field_name;
+        ));
+
         field_names.push(f.name);
+        // Aliases are never written back, so they shouldn't end up in the rest fields either.
+        field_names.extend(f.aliases);
+        field_names.extend(f.id_properties);
         field_idents.push(f.ident);
+        field_validators.push(f.validator);
         field_converters.push(f.converter);
         field_inserters.push(f.inserter);
+        field_ref_inserters.push(f.ref_inserter);
+        field_change_inserters.push(f.change_inserter);
 
-        if let Some(field_accessor) = f.accessor {
+        if let (Some(field_accessor), Some(field_drainer)) = (f.accessor, f.drainer) {
             field_accessors.push(field_accessor);
+            field_drainers.push(field_drainer);
         } else {
             has_full_accessor_impl = false;
         }
@@ -646,6 +2090,10 @@ pub fn derive_object_shape(input: TokenStream) -> TokenStream {
         if let Some(related_impl) = f.related_impl {
             field_related_impls.push(related_impl);
         }
+
+        if let Some(query_methods) = f.query_methods {
+            field_query_methods.push(query_methods);
+        }
     }
 
     let rest_field_try_from = if let Some(ref rest_field_ident) = rest_field_ident {
@@ -662,6 +2110,46 @@ pub fn derive_object_shape(input: TokenStream) -> TokenStream {
         quote!()
     };
 
+    let rest_field_changes = if let Some(ref rest_field_ident) = rest_field_ident {
+        quote!(
+            for (k, v) in other.#rest_field_ident.iter() {
+                if self.#rest_field_ident.get(k) != Some(v) {
+                    result.insert(k.clone(), v.clone());
+                }
+            }
+        )
+    } else {
+        quote!()
+    };
+
+    // When converting from a borrowed object, only the rest fields are cloned, rather than the
+    // entire object.
+    let rest_field_try_from_ref = if let Some(ref rest_field_ident) = rest_field_ident {
+        quote!(
+            ,#rest_field_ident: object.iter().filter_map(|(k, v)| {
+                if (#(k == #field_names)||*) {
+                    None
+                } else {
+                    Some((k.clone(), v.clone()))
+                }
+            }).collect()
+        )
+    } else {
+        quote!()
+    };
+
+    // When draining a mutable object, the properties of string fields have already been removed,
+    // but those of other fields are left in place for the rest fields to skip.
+    let rest_field_try_from_drain = if let Some(ref rest_field_ident) = rest_field_ident {
+        quote!(
+            ,#rest_field_ident: object.drain().filter(|(k, _v)| {
+                !(#(k == #field_names)||*)
+            }).collect()
+        )
+    } else {
+        quote!()
+    };
+
     let rest_field_into = if let Some(ref rest_field_ident) = rest_field_ident {
         quote!(
             result.extend(self.#rest_field_ident.into_iter());
@@ -670,27 +2158,54 @@ pub fn derive_object_shape(input: TokenStream) -> TokenStream {
         quote!()
     };
 
+    let rest_field_to_object = if let Some(ref rest_field_ident) = rest_field_ident {
+        quote!(
+            result.extend(
+                self.#rest_field_ident
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.clone())),
+            );
+        )
+    } else {
+        quote!()
+    };
+
+    // Each field inserts at most one property, so the object can be allocated once up front
+    // rather than growing as properties are inserted.
+    let into_capacity = fixed_field_names.len() + field_inserters.len();
+    let rest_field_capacity = if let Some(ref rest_field_ident) = rest_field_ident {
+        quote!(+ self.#rest_field_ident.len())
+    } else {
+        quote!()
+    };
+
+    let is_lossless = rest_field_ident.is_some();
+
     let try_from_impl = if has_full_accessor_impl {
+        let fixed_field_checks = quote! {
+            #(
+                {
+                    let value = #fixed_field_accessors;
+
+                    if value != #fixed_field_values {
+                        return Err(
+                            qualia::ConversionError::FixedFieldWrongValue(
+                                #fixed_field_names.to_string(),
+                                #fixed_field_values.into(),
+                                value.into(),
+                            )
+                        );
+                    }
+                }
+            )*
+        };
+
         quote! {
             impl std::convert::TryFrom<qualia::Object> for #orig_type_name {
                 type Error = qualia::ConversionError;
 
                 fn try_from(object: qualia::Object) -> std::result::Result<#orig_type_name, qualia::ConversionError> {
-                    #(
-                        {
-                            let value = #fixed_field_accessors;
-
-                            if value != #fixed_field_values {
-                                return Err(
-                                    qualia::ConversionError::FixedFieldWrongValue(
-                                        #fixed_field_names.to_string(),
-                                        #fixed_field_values.into(),
-                                        value.into(),
-                                    )
-                                );
-                            }
-                        }
-                    )*
+                    #fixed_field_checks
 
                     Ok(#orig_type_name {
                         #(#field_idents: #field_accessors),*
@@ -702,8 +2217,26 @@ pub fn derive_object_shape(input: TokenStream) -> TokenStream {
             impl std::convert::TryFrom<&qualia::Object> for #orig_type_name {
                 type Error = qualia::ConversionError;
 
-                fn try_from(orig_object: &qualia::Object) -> std::result::Result<#orig_type_name, qualia::ConversionError> {
-                    #orig_type_name::try_from(orig_object.clone())
+                fn try_from(object: &qualia::Object) -> std::result::Result<#orig_type_name, qualia::ConversionError> {
+                    #fixed_field_checks
+
+                    Ok(#orig_type_name {
+                        #(#field_idents: #field_accessors),*
+                        #rest_field_try_from_ref
+                    })
+                }
+            }
+
+            impl std::convert::TryFrom<&mut qualia::Object> for #orig_type_name {
+                type Error = qualia::ConversionError;
+
+                fn try_from(object: &mut qualia::Object) -> std::result::Result<#orig_type_name, qualia::ConversionError> {
+                    #fixed_field_checks
+
+                    Ok(#orig_type_name {
+                        #(#field_idents: #field_drainers),*
+                        #rest_field_try_from_drain
+                    })
                 }
             }
 
@@ -713,12 +2246,59 @@ pub fn derive_object_shape(input: TokenStream) -> TokenStream {
         quote!()
     };
 
+    let typed_query_impl = if parsed_struct
+        .attrs
+        .iter()
+        .any(|attr| attr.style == syn::AttrStyle::Outer && attr.path.is_ident("typed_query"))
+    {
+        let query_type_name = format_ident!("{}Query", orig_type_name);
+
+        quote!(
+            impl #orig_type_name {
+                /// Get a typed query builder for objects of this shape.
+                #[allow(dead_code)]
+                #orig_type_vis fn query() -> #query_type_name {
+                    #query_type_name(<#orig_type_name as qualia::Queryable>::q())
+                }
+            }
+
+            /// A query builder with methods for each field of
+            #[doc = concat!("[`", stringify!(#orig_type_name), "`].")]
+            #[allow(dead_code)]
+            #orig_type_vis struct #query_type_name(qualia::query_builder::QueryBuilder);
+
+            #[allow(dead_code)]
+            impl #query_type_name {
+                #(#field_query_methods)*
+
+                /// Consume this query builder and build a [`QueryNode`](qualia::query::QueryNode).
+                pub fn build(self) -> qualia::query::QueryNode {
+                    self.0.build()
+                }
+            }
+
+            impl std::convert::From<#query_type_name> for qualia::query::QueryNode {
+                fn from(query: #query_type_name) -> qualia::query::QueryNode {
+                    query.build()
+                }
+            }
+        )
+    } else {
+        quote!()
+    };
+
     let result = quote!(
         #prologue
 
         #try_from_impl
 
         impl qualia::ObjectShape for #orig_type_name {
+            const USES_OBJECT_ID: bool = #uses_object_id;
+            const UNIQUE_FIELDS: &'static [&'static str] = &[#(#unique_properties),*];
+            const PROPERTIES: &'static [(&'static str, qualia::PropKind)] = &[
+                #((#property_names, #property_kinds)),*
+            ];
+
             fn try_convert(object: qualia::Object, store: &qualia::Store) -> std::result::Result<#orig_type_name, qualia::StoreError> {
                 #(
                     {
@@ -745,12 +2325,9 @@ pub fn derive_object_shape(input: TokenStream) -> TokenStream {
 
         impl std::convert::Into<qualia::Object> for #orig_type_name {
             fn into(self) -> qualia::Object {
-                use qualia::{object, Object};
-
                 #[allow(unused_mut)]
-                let mut result = object!(
-                    #(#fixed_field_names => #fixed_field_values),*
-                );
+                let mut result = qualia::Object::with_capacity(#into_capacity #rest_field_capacity);
+                #(result.insert(#fixed_field_names.into(), #fixed_field_values.into());)*
                 #(#field_inserters)*
                 #rest_field_into
 
@@ -758,21 +2335,128 @@ pub fn derive_object_shape(input: TokenStream) -> TokenStream {
             }
         }
 
-        impl qualia::Queryable for #orig_type_name {
-            fn q() -> qualia::query_builder::QueryBuilder {
-                qualia::Q
+        #queryable_impl
+
+        #builder_impl
+
+        impl #orig_type_name {
+            #(#field_name_consts)*
+
+            /// Convert this shape into an [`Object`](qualia::Object).
+            ///
+            /// This is the same as `.into()`, but doesn't need a type annotation.
+            #[allow(dead_code)]
+            #orig_type_vis fn into_object(self) -> qualia::Object {
+                self.into()
+            }
+
+            /// Convert a copy of this shape into an [`Object`](qualia::Object), leaving the shape
+            /// as it is.
+            #[allow(dead_code)]
+            #orig_type_vis fn to_object(&self) -> qualia::Object {
+                #[allow(unused_mut)]
+                let mut result = qualia::Object::with_capacity(#into_capacity #rest_field_capacity);
+                #(result.insert(#fixed_field_names.into(), #fixed_field_values.into());)*
+                #(#field_ref_inserters)*
+                #rest_field_to_object
+
+                result
+            }
+
+            #(#field_related_impls)*
+
+            /// Get the properties of `other` that differ from this object, to pass as a patch to
+            /// [`MutableCollection::set()`](qualia::MutableCollection::set).
+            ///
+            /// Fixed fields and the `object_id` are never included. As `set()` cannot remove
+            /// properties, properties that `other` lacks are not represented.
+            #[allow(dead_code)]
+            #orig_type_vis fn changed_fields(&self, other: &Self) -> qualia::Object {
+                #[allow(unused_mut)]
+                let mut result = qualia::Object::new();
+                #(#field_change_inserters)*
+                #rest_field_changes
+
+                result
+            }
+
+            /// Check that the given object can be converted to this shape, returning every problem
+            /// found rather than only the first.
+            ///
+            /// Related objects are only checked for a valid ID, as they cannot be looked up
+            /// without a store.
+            #[allow(dead_code)]
+            #orig_type_vis fn validate(
+                object: &qualia::Object,
+            ) -> std::result::Result<(), std::vec::Vec<qualia::ConversionError>> {
+                let mut errors = std::vec::Vec::new();
+
                 #(
-                    .equal(
-                        #fixed_field_names,
-                        #fixed_field_values,
-                    )
+                    if let Err(e) = (|| -> std::result::Result<(), qualia::ConversionError> {
+                        let value = #fixed_field_accessors;
+
+                        if value != #fixed_field_values {
+                            return Err(qualia::ConversionError::FixedFieldWrongValue(
+                                #fixed_field_names.to_string(),
+                                #fixed_field_values.into(),
+                                value.into(),
+                            ));
+                        }
+
+                        Ok(())
+                    })() {
+                        errors.push(e);
+                    }
+                )*
+
+                #(
+                    if let Err(e) = (|| -> std::result::Result<(), qualia::ConversionError> {
+                        let _ = #field_validators;
+
+                        Ok(())
+                    })() {
+                        errors.push(e);
+                    }
                 )*
+
+                if errors.is_empty() {
+                    Ok(())
+                } else {
+                    Err(errors)
+                }
             }
-        }
 
-        impl #orig_type_name {
-            #(#field_related_impls)*
+            /// Whether converting any object to this shape and back keeps all of its properties,
+            /// which is only the case for shapes with a `#[rest_fields]` field.
+            #[allow(dead_code)]
+            #orig_type_vis fn is_lossless() -> bool {
+                #is_lossless
+            }
+
+            /// Get the sorted names of the properties of `object` that would be dropped by
+            /// converting it to this shape and back.
+            ///
+            /// The `object_id` is not included, as it is kept by the store regardless of shape.
+            #[allow(dead_code)]
+            #orig_type_vis fn would_lose(object: &qualia::Object) -> std::vec::Vec<std::string::String> {
+                if #is_lossless {
+                    return std::vec::Vec::new();
+                }
+
+                let mut lost: std::vec::Vec<_> = object
+                    .keys()
+                    .filter(|k| {
+                        !(*k == "object_id" #(|| *k == #field_names)* #(|| *k == #fixed_field_names)*)
+                    })
+                    .cloned()
+                    .collect();
+                lost.sort();
+
+                lost
+            }
         }
+
+        #typed_query_impl
     ).into();
     // eprintln!("");
     // eprintln!("{}", result);
@@ -0,0 +1,215 @@
+use qualia::{object, ConversionError, Object, PropValue};
+use qualia_derive::ObjectShape;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::convert::TryFrom;
+
+struct CountingAllocator;
+
+// Allocations are counted per thread, as tests run in parallel.
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// The number of allocations made by the current thread so far.
+fn allocation_count() -> usize {
+    ALLOCATIONS.with(Cell::get)
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // The count is unavailable while the thread is being torn down.
+        let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[test]
+fn converting_borrowed_objects_only_clones_needed_fields() -> Result<(), ConversionError> {
+    #[derive(Debug, ObjectShape, PartialEq)]
+    struct NarrowShape {
+        name: String,
+        width: i64,
+    }
+
+    let mut wide_object = object!("name" => "letter", "width" => 8);
+    for i in 0..1000 {
+        wide_object.insert(
+            format!("extra-{}", i),
+            PropValue::String(format!("value {}", i)),
+        );
+    }
+
+    let before = allocation_count();
+    let shape = NarrowShape::try_from(&wide_object)?;
+    let allocations = allocation_count() - before;
+
+    assert_eq!(
+        shape,
+        NarrowShape {
+            name: "letter".to_string(),
+            width: 8,
+        }
+    );
+    // Only the `name` string should be cloned, not the 2000 strings in the rest of the object.
+    assert!(allocations < 10, "{} allocations", allocations);
+
+    Ok(())
+}
+
+#[test]
+fn converting_wide_shapes_to_objects_allocates_once() {
+    #[derive(ObjectShape)]
+    struct WideShape {
+        f01: i64,
+        f02: i64,
+        f03: i64,
+        f04: i64,
+        f05: i64,
+        f06: i64,
+        f07: i64,
+        f08: i64,
+        f09: i64,
+        f10: i64,
+        f11: i64,
+        f12: i64,
+        f13: i64,
+        f14: i64,
+        f15: i64,
+        f16: i64,
+        f17: i64,
+        f18: i64,
+        f19: i64,
+        f20: i64,
+        f21: i64,
+        f22: i64,
+        f23: i64,
+        f24: i64,
+        f25: i64,
+        f26: i64,
+        f27: i64,
+        f28: i64,
+        f29: i64,
+        f30: i64,
+    }
+
+    let shape = WideShape {
+        f01: 1,
+        f02: 2,
+        f03: 3,
+        f04: 4,
+        f05: 5,
+        f06: 6,
+        f07: 7,
+        f08: 8,
+        f09: 9,
+        f10: 10,
+        f11: 11,
+        f12: 12,
+        f13: 13,
+        f14: 14,
+        f15: 15,
+        f16: 16,
+        f17: 17,
+        f18: 18,
+        f19: 19,
+        f20: 20,
+        f21: 21,
+        f22: 22,
+        f23: 23,
+        f24: 24,
+        f25: 25,
+        f26: 26,
+        f27: 27,
+        f28: 28,
+        f29: 29,
+        f30: 30,
+    };
+
+    let before = allocation_count();
+    let object: Object = shape.into();
+    let allocations = allocation_count() - before;
+
+    assert_eq!(object.len(), 30);
+    assert_eq!(object["f30"], PropValue::Number(30));
+    // One allocation for each property name, and one for the object itself.
+    assert!(allocations <= 31, "{} allocations", allocations);
+}
+
+#[test]
+fn converting_mutable_objects_moves_strings_out() -> Result<(), ConversionError> {
+    #[derive(Debug, ObjectShape, PartialEq)]
+    struct Document {
+        title: String,
+        #[field("body", alias = "text")]
+        body: String,
+        pages: i64,
+    }
+
+    let mut object = object!(
+        "title" => "notes",
+        "text" => "a".repeat(10_000),
+        "pages" => 3,
+        "author" => "pat",
+    );
+    let body_pointer = object["text"].as_str().unwrap().as_ptr();
+
+    let before = allocation_count();
+    let document = Document::try_from(&mut object)?;
+    let allocations = allocation_count() - before;
+
+    assert_eq!(document.body.as_ptr(), body_pointer);
+    assert_eq!(document.title, "notes");
+    assert_eq!(document.pages, 3);
+    assert_eq!(allocations, 0);
+    assert_eq!(object, object!("pages" => 3, "author" => "pat"));
+
+    let mut wrong_type = object!("title" => 1, "body" => "text", "pages" => 3);
+    assert_eq!(
+        Document::try_from(&mut wrong_type),
+        Err(ConversionError::FieldWrongType(
+            "title".to_string(),
+            "string".to_string(),
+            "number".to_string()
+        ))
+    );
+    assert_eq!(
+        wrong_type,
+        object!("title" => 1, "body" => "text", "pages" => 3)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn draining_into_rest_fields_takes_remaining_properties() -> Result<(), ConversionError> {
+    #[derive(Debug, ObjectShape, PartialEq)]
+    struct Tagged {
+        name: String,
+        size: i64,
+        #[rest_fields]
+        rest: Object,
+    }
+
+    let mut object = object!("name" => "box", "size" => 2, "color" => "red");
+    let tagged = Tagged::try_from(&mut object)?;
+
+    assert_eq!(
+        tagged,
+        Tagged {
+            name: "box".to_string(),
+            size: 2,
+            rest: object!("color" => "red"),
+        }
+    );
+    assert!(object.is_empty());
+
+    Ok(())
+}
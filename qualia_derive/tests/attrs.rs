@@ -1,6 +1,9 @@
 use predicates::prelude::*;
-use qualia::{object, ConversionError, Object, Result};
-use qualia_derive::ObjectShape;
+use qualia::query::QueryError;
+use qualia::{
+    object, ConversionError, Object, ObjectShape, ObjectShapeWithId, PropEnum, PropKind, Queryable,
+    Result, Store, StoreError, Q,
+};
 use std::convert::TryFrom;
 
 macro_rules! assert_is_err_matching {
@@ -54,6 +57,127 @@ fn can_convert_with_custom_field_names() -> Result<(), ConversionError> {
     Ok(())
 }
 
+#[test]
+fn can_read_renamed_fields_from_aliases() -> Result<(), ConversionError> {
+    #[derive(Debug, ObjectShape, PartialEq)]
+    struct AliasedShape {
+        #[field("shape-name", alias = "my-name", alias = "name")]
+        name: String,
+        #[field(alias = "size")]
+        width: i64,
+        #[rest_fields]
+        rest: Object,
+    }
+
+    let shape =
+        AliasedShape::try_from(object!("name" => "letter", "size" => 8, "kind" => "paper"))?;
+
+    assert_eq!(
+        shape,
+        AliasedShape {
+            name: "letter".to_string(),
+            width: 8,
+            rest: object!("kind" => "paper"),
+        }
+    );
+
+    let obj: Object = shape.into();
+    assert_eq!(
+        obj,
+        object!("shape-name" => "letter", "width" => 8, "kind" => "paper")
+    );
+
+    assert_eq!(
+        AliasedShape::try_from(object!("shape-name" => "new", "my-name" => "old", "width" => 8))?
+            .name,
+        "new"
+    );
+
+    assert_eq!(
+        AliasedShape::try_from(object!("width" => 8)),
+        Err(ConversionError::FieldMissing("shape-name".to_string()))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn can_convert_with_custom_object_id_field() -> Result<(), ConversionError> {
+    #[derive(Debug, ObjectShape, PartialEq)]
+    struct RenamedIdShape {
+        #[object_id]
+        id: Option<i64>,
+        name: String,
+    }
+
+    let mut shape = RenamedIdShape::try_from(object!("object_id" => 7, "name" => "letter"))?;
+
+    assert_eq!(
+        shape,
+        RenamedIdShape {
+            id: Some(7),
+            name: "letter".to_string(),
+        }
+    );
+    assert_eq!(shape.get_object_id(), Some(7));
+
+    shape.set_object_id(8);
+    assert_eq!(shape.id, Some(8));
+
+    let obj: Object = shape.into();
+    assert_eq!(obj, object!("object_id" => 8, "name" => "letter"));
+
+    Ok(())
+}
+
+#[test]
+fn can_read_object_id_into_non_optional_field() -> Result<(), StoreError> {
+    #[derive(Debug, ObjectShape, PartialEq)]
+    struct ReadOnlyShape {
+        #[object_id]
+        id: i64,
+        name: String,
+    }
+
+    assert_eq!(
+        ReadOnlyShape::try_from(object!("object_id" => 7, "name" => "letter"))?,
+        ReadOnlyShape {
+            id: 7,
+            name: "letter".to_string(),
+        }
+    );
+    assert_eq!(
+        ReadOnlyShape::try_from(object!("name" => "letter")),
+        Err(ConversionError::FieldMissing("object_id".to_string()))
+    );
+
+    let test_dir = tempfile::tempdir().unwrap();
+    let mut store = Store::open(test_dir.path().join("store.qualia"))?;
+
+    let checkpoint = store.checkpoint()?;
+    let first_id = checkpoint.add(object!("name" => "first"))?;
+    let second_id = checkpoint.add(object!("name" => "second"))?;
+    checkpoint.commit("add objects")?;
+
+    let mut shapes: Vec<ReadOnlyShape> = store.all().iter_as()?.collect();
+    shapes.sort_by_key(|shape| shape.id);
+    assert_eq!(
+        shapes,
+        [
+            ReadOnlyShape {
+                id: first_id,
+                name: "first".to_string(),
+            },
+            ReadOnlyShape {
+                id: second_id,
+                name: "second".to_string(),
+            },
+        ]
+    );
+
+    Ok(())
+}
+
 #[test]
 fn can_convert_with_fixed_fields() -> Result<(), ConversionError> {
     #[derive(Debug, ObjectShape, PartialEq)]
@@ -245,3 +369,622 @@ fn can_get_related() -> Result<(), ConversionError> {
 
     Ok(())
 }
+
+#[test]
+fn can_get_optional_related() -> Result<(), StoreError> {
+    #[derive(Clone, Debug, ObjectShape, PartialEq)]
+    struct ShapeGroup {
+        object_id: Option<i64>,
+        name: String,
+    }
+
+    #[derive(Debug, ObjectShape, PartialEq)]
+    struct CustomShape {
+        #[related(ShapeGroup)]
+        shape_group_id: Option<i64>,
+        width: i64,
+    }
+
+    let test_dir = tempfile::tempdir().unwrap();
+    let mut store = Store::open(test_dir.path().join("store.qualia"))?;
+
+    let mut group = ShapeGroup {
+        object_id: None,
+        name: "letters".to_string(),
+    };
+    let checkpoint = store.checkpoint()?;
+    checkpoint.add_with_id(&mut group)?;
+    checkpoint.commit("add group")?;
+
+    let grouped =
+        CustomShape::try_from(object!("shape_group_id" => group.object_id.unwrap(), "width" => 8))?;
+    assert_eq!(grouped.fetch_shape_group(&store)?, Some(group));
+
+    let ungrouped = CustomShape::try_from(object!("width" => 8))?;
+    assert_eq!(ungrouped.shape_group_id, None);
+    assert_eq!(ungrouped.fetch_shape_group(&store)?, None);
+
+    let obj: Object = ungrouped.into();
+    assert_eq!(obj, object!("width" => 8));
+
+    Ok(())
+}
+
+#[test]
+fn related_and_referenced_fields_can_use_custom_id_properties() -> Result<(), StoreError> {
+    #[derive(Clone, Debug, ObjectShape, PartialEq)]
+    struct ParentShape {
+        object_id: Option<i64>,
+        name: String,
+    }
+
+    #[derive(Debug, ObjectShape, PartialEq)]
+    struct RelatedShape {
+        #[related(ParentShape, id_field = "parent_object")]
+        parent: i64,
+    }
+
+    #[derive(Debug, ObjectShape, PartialEq)]
+    struct ReferencingShape {
+        #[referenced(id_field = "parent_object")]
+        parent: ParentShape,
+        #[rest_fields]
+        rest: Object,
+    }
+
+    let test_dir = tempfile::tempdir().unwrap();
+    let mut store = Store::open(test_dir.path().join("store.qualia"))?;
+
+    let mut parent = ParentShape {
+        object_id: None,
+        name: "parent".to_string(),
+    };
+    let checkpoint = store.checkpoint()?;
+    checkpoint.add_with_id(&mut parent)?;
+    let parent_id = parent.object_id.unwrap();
+    let child_id = checkpoint.add(object!("parent_object" => parent_id, "size" => 2))?;
+    checkpoint.commit("add parent and child")?;
+
+    let related = RelatedShape::try_from(object!("parent_object" => parent_id))?;
+    assert_eq!(related.parent, parent_id);
+    assert_eq!(related.fetch_parent(&store)?, parent);
+    assert_eq!(RelatedShape::PARENT_FIELD, "parent_object");
+
+    let referencing: ReferencingShape = store.query(Q.id(child_id)).one_converted(&store)?;
+    assert_eq!(referencing.parent, parent);
+    assert_eq!(
+        referencing.rest,
+        object!("object_id" => child_id, "size" => 2)
+    );
+
+    let obj: Object = referencing.into();
+    assert_eq!(
+        obj,
+        object!("object_id" => child_id, "parent_object" => parent_id, "size" => 2)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn referenced_fields_can_be_boxed() -> Result<(), StoreError> {
+    #[derive(Clone, Debug, ObjectShape, PartialEq)]
+    struct ParentShape {
+        object_id: Option<i64>,
+        name: String,
+    }
+
+    #[derive(Clone, Debug, ObjectShape, PartialEq)]
+    struct BoxedShape {
+        object_id: Option<i64>,
+        parent: Box<ParentShape>,
+        shared_parent: std::rc::Rc<ParentShape>,
+    }
+
+    let test_dir = tempfile::tempdir().unwrap();
+    let mut store = Store::open(test_dir.path().join("store.qualia"))?;
+
+    let mut parent = ParentShape {
+        object_id: None,
+        name: "parent".to_string(),
+    };
+    let checkpoint = store.checkpoint()?;
+    checkpoint.add_with_id(&mut parent)?;
+    let parent_id = parent.object_id.unwrap();
+    let mut boxed = BoxedShape {
+        object_id: None,
+        parent: Box::new(parent.clone()),
+        shared_parent: std::rc::Rc::new(parent.clone()),
+    };
+    checkpoint.add_with_id(&mut boxed)?;
+    checkpoint.commit("add parent and child")?;
+
+    let fetched: BoxedShape = store
+        .query(Q.id(boxed.object_id.unwrap()))
+        .one_converted(&store)?;
+    assert_eq!(fetched.parent, Box::new(parent.clone()));
+    assert_eq!(*fetched.shared_parent, parent);
+
+    let obj: Object = fetched.into();
+    assert_eq!(
+        obj,
+        object!(
+            "object_id" => boxed.object_id.unwrap(),
+            "parent_id" => parent_id,
+            "shared_parent_id" => parent_id,
+        )
+    );
+
+    Ok(())
+}
+
+#[test]
+fn can_convert_json_string_fields() -> Result<(), ConversionError> {
+    #[derive(Debug, ObjectShape, PartialEq)]
+    struct Article {
+        title: String,
+        #[json]
+        #[field("tag-list")]
+        tags: Vec<String>,
+    }
+
+    let article = Article {
+        title: "Hello".to_string(),
+        tags: vec!["greeting".to_string(), "short \"quoted\"".to_string()],
+    };
+    let obj: Object = Article {
+        title: article.title.clone(),
+        tags: article.tags.clone(),
+    }
+    .into();
+
+    assert_eq!(
+        obj,
+        object!(
+            "title" => "Hello",
+            "tag-list" => r#"["greeting","short \"quoted\""]"#,
+        )
+    );
+    assert_eq!(Article::try_from(obj)?, article);
+
+    assert_eq!(
+        Article::try_from(object!("title" => "Hello", "tag-list" => "[\"unclosed\"")),
+        Err(ConversionError::FieldWrongType(
+            "tag-list".to_string(),
+            "JSON string".to_string(),
+            "string".to_string()
+        ))
+    );
+    assert_eq!(
+        Article::try_from(object!("title" => "Hello", "tag-list" => 1)),
+        Err(ConversionError::FieldWrongType(
+            "tag-list".to_string(),
+            "JSON string".to_string(),
+            "number".to_string()
+        ))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn can_convert_bignum_fields() -> Result<(), ConversionError> {
+    #[derive(Clone, Debug, ObjectShape, PartialEq)]
+    #[typed_query]
+    struct Device {
+        #[bignum]
+        serial: u64,
+        #[bignum]
+        #[field("big-offset")]
+        offset: i128,
+    }
+
+    let device = Device {
+        serial: u64::MAX - 1,
+        offset: -(i64::MAX as i128) * 4,
+    };
+    let obj: Object = device.clone().into();
+
+    assert_eq!(
+        obj,
+        object!(
+            "serial" => "18446744073709551614",
+            "big-offset" => "-36893488147419103228",
+        )
+    );
+    assert_eq!(Device::try_from(obj)?, device);
+
+    assert_eq!(
+        Device::try_from(object!("serial" => "18446744073709551616", "big-offset" => "0")),
+        Err(ConversionError::FieldWrongType(
+            "serial".to_string(),
+            "decimal string".to_string(),
+            "string".to_string()
+        ))
+    );
+    assert_eq!(
+        Device::try_from(object!("serial" => 12, "big-offset" => "0")),
+        Err(ConversionError::FieldWrongType(
+            "serial".to_string(),
+            "decimal string".to_string(),
+            "number".to_string()
+        ))
+    );
+    assert_eq!(
+        Device::query().serial(u64::MAX).build(),
+        Q.equal("serial", "18446744073709551615").build()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn default_values_can_be_skipped() -> Result<(), ConversionError> {
+    #[derive(Clone, Debug, ObjectShape, PartialEq)]
+    struct Task {
+        name: String,
+        #[skip_if_default]
+        #[field("due-at")]
+        due_at: i64,
+        #[skip_if_default]
+        notes: String,
+        #[skip_if_default]
+        #[bignum]
+        estimate: u64,
+    }
+
+    let task = Task {
+        name: "Write docs".to_string(),
+        due_at: 0,
+        notes: "".to_string(),
+        estimate: 0,
+    };
+    let obj: Object = task.clone().into();
+    assert_eq!(obj, object!("name" => "Write docs"));
+    assert_eq!(task.to_object(), obj);
+    assert_eq!(Task::try_from(obj)?, task);
+
+    let task = Task {
+        name: "Write docs".to_string(),
+        due_at: 12,
+        notes: "soon".to_string(),
+        estimate: 3,
+    };
+    let obj: Object = task.clone().into();
+    assert_eq!(
+        obj,
+        object!("name" => "Write docs", "due-at" => 12, "notes" => "soon", "estimate" => "3")
+    );
+    assert_eq!(Task::try_from(obj)?, task);
+
+    assert_eq!(
+        Task::try_from(object!("name" => "Write docs", "notes" => 5)),
+        Err(ConversionError::FieldWrongType(
+            "notes".to_string(),
+            "string".to_string(),
+            "number".to_string()
+        ))
+    );
+    assert_eq!(
+        Task::try_from(object!("notes" => "soon")),
+        Err(ConversionError::FieldMissing("name".to_string()))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn unique_fields_are_enforced_on_insert() -> Result<(), StoreError> {
+    #[derive(Clone, Debug, ObjectShape, PartialEq)]
+    #[fixed_fields("kind" => "file")]
+    struct File {
+        object_id: Option<i64>,
+        #[unique]
+        hash: String,
+        name: String,
+    }
+
+    let test_dir = tempfile::tempdir().unwrap();
+    let mut store = Store::open(test_dir.path().join("store.qualia"))?;
+
+    let checkpoint = store.checkpoint()?;
+    checkpoint.add(object!("kind" => "link", "hash" => "abc"))?;
+    checkpoint.add_with_id(&mut File {
+        object_id: None,
+        hash: "abc".to_string(),
+        name: "a.txt".to_string(),
+    })?;
+
+    let mut duplicate = File {
+        object_id: None,
+        hash: "abc".to_string(),
+        name: "b.txt".to_string(),
+    };
+    assert!(matches!(
+        checkpoint.add_with_id(&mut duplicate),
+        Err(StoreError::Duplicate { field, value }) if field == "hash" && value == "abc".into()
+    ));
+    assert_eq!(duplicate.object_id, None);
+
+    checkpoint.add_with_id(&mut File {
+        object_id: None,
+        hash: "def".to_string(),
+        name: "b.txt".to_string(),
+    })?;
+    checkpoint.commit("add files")?;
+
+    assert_eq!(store.query(File::q()).len()?, 2);
+
+    Ok(())
+}
+
+#[test]
+fn object_id_is_only_fetched_when_used() -> Result<(), StoreError> {
+    #[derive(Debug, ObjectShape, PartialEq)]
+    struct Plain {
+        name: String,
+    }
+
+    #[derive(Debug, ObjectShape, PartialEq)]
+    struct WithId {
+        object_id: Option<i64>,
+        name: String,
+    }
+
+    #[derive(Debug, ObjectShape, PartialEq)]
+    struct WithRest {
+        name: String,
+        #[rest_fields]
+        rest: Object,
+    }
+
+    assert_eq!(
+        [
+            Plain::USES_OBJECT_ID,
+            WithId::USES_OBJECT_ID,
+            WithRest::USES_OBJECT_ID
+        ],
+        [false, true, true]
+    );
+
+    let test_dir = tempfile::tempdir().unwrap();
+    let mut store = Store::open(test_dir.path().join("store.qualia"))?;
+
+    let checkpoint = store.checkpoint()?;
+    let object_id = checkpoint.add(object!("name" => "letter"))?;
+    checkpoint.commit("add letter")?;
+
+    assert_eq!(
+        store.all().iter_as::<Plain>()?.collect::<Vec<_>>(),
+        vec![Plain {
+            name: "letter".to_string()
+        }]
+    );
+    assert_eq!(
+        store.all().one_as::<WithId>()?,
+        WithId {
+            object_id: Some(object_id),
+            name: "letter".to_string()
+        }
+    );
+    assert_eq!(
+        store.all().one_as::<WithRest>()?.rest,
+        object!("object_id" => object_id)
+    );
+
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, PropEnum)]
+#[prop_enum(rename_all = "kebab-case")]
+enum Status {
+    Active,
+    LongArchived,
+}
+
+#[test]
+fn can_convert_enum_fields() -> Result<(), ConversionError> {
+    #[derive(Debug, ObjectShape, PartialEq)]
+    #[typed_query]
+    struct Project {
+        name: String,
+        #[prop_enum]
+        status: Status,
+    }
+
+    assert_eq!(Status::VARIANTS, &["active", "long-archived"]);
+
+    let project = Project::try_from(object!("name" => "qualia", "status" => "long-archived"))?;
+    assert_eq!(
+        project,
+        Project {
+            name: "qualia".to_string(),
+            status: Status::LongArchived,
+        }
+    );
+
+    let obj: Object = project.into();
+    assert_eq!(
+        obj,
+        object!("name" => "qualia", "status" => "long-archived")
+    );
+
+    assert_eq!(
+        Project::query().status(Status::Active).build(),
+        Q.equal("status", "active").build()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn converting_fails_for_unknown_enum_values() -> Result<(), ConversionError> {
+    #[derive(Debug, ObjectShape, PartialEq)]
+    struct Project {
+        #[prop_enum]
+        status: Status,
+    }
+
+    assert_eq!(
+        Project::try_from(object!("status" => "LongArchived")),
+        Err(ConversionError::FieldWrongType(
+            "status".to_string(),
+            "one of active, long-archived".to_string(),
+            "string".to_string()
+        ))
+    );
+
+    assert_eq!(
+        Project::try_from(object!("status" => 1)),
+        Err(ConversionError::FieldWrongType(
+            "status".to_string(),
+            "one of active, long-archived".to_string(),
+            "number".to_string()
+        ))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn property_kinds_are_listed() {
+    #[derive(Clone, Debug, ObjectShape, PartialEq)]
+    struct ParentShape {
+        object_id: Option<i64>,
+        name: String,
+    }
+
+    #[derive(Debug, ObjectShape, PartialEq)]
+    #[fixed_fields("kind" => "mixed")]
+    struct MixedShape {
+        object_id: Option<i64>,
+        #[field("title")]
+        name: String,
+        size: i64,
+        mode: Option<i64>,
+        #[prop_enum]
+        status: Status,
+        #[json]
+        tags: Vec<String>,
+        #[bignum]
+        serial: u64,
+        #[referenced]
+        parent: ParentShape,
+        #[rest_fields]
+        rest: Object,
+    }
+
+    assert_eq!(
+        MixedShape::PROPERTIES,
+        &[
+            ("title", PropKind::String),
+            ("size", PropKind::Number),
+            ("mode", PropKind::OptionalNumber),
+            ("status", PropKind::Enum(&["active", "long-archived"])),
+            ("tags", PropKind::Json),
+            ("serial", PropKind::BigNum),
+            ("parent_id", PropKind::Object),
+        ]
+    );
+}
+
+#[test]
+fn queries_can_be_checked_against_shapes() {
+    #[derive(Debug, ObjectShape, PartialEq)]
+    #[fixed_fields("kind" => "file")]
+    struct File {
+        object_id: Option<i64>,
+        name: String,
+        size: i64,
+    }
+
+    assert_eq!(
+        Q.equal("kind", "file")
+            .equal("name", "notes.txt")
+            .greater_than("size", 10)
+            .id(4)
+            .build_for::<File>(),
+        Ok(Q.equal("kind", "file")
+            .equal("name", "notes.txt")
+            .greater_than("size", 10)
+            .id(4)
+            .build())
+    );
+
+    assert_eq!(
+        Q.equal("name", "notes.txt")
+            .and(|q| q.greater_than("szie", 10))
+            .build_for::<File>(),
+        Err(QueryError::UnknownProperty {
+            name: "szie".to_string(),
+            shape: std::any::type_name::<File>().to_string(),
+        })
+    );
+}
+
+#[test]
+fn properties_can_share_a_prefix() -> Result<(), StoreError> {
+    #[derive(Clone, Debug, ObjectShape, PartialEq)]
+    #[prefix("photo.")]
+    #[fixed_fields("kind" => "photo")]
+    #[typed_query]
+    struct Photo {
+        object_id: Option<i64>,
+        #[field("w", alias = "wide")]
+        width: i64,
+        height: i64,
+    }
+
+    let photo = Photo::try_from(object!(
+        "object_id" => 3,
+        "kind" => "photo",
+        "photo.wide" => 4,
+        "photo.height" => 3,
+    ))?;
+    assert_eq!(
+        photo,
+        Photo {
+            object_id: Some(3),
+            width: 4,
+            height: 3,
+        }
+    );
+    let obj: Object = photo.into();
+    assert_eq!(
+        obj,
+        object!("object_id" => 3, "kind" => "photo", "photo.w" => 4, "photo.height" => 3)
+    );
+
+    let test_dir = tempfile::tempdir().unwrap();
+    let mut store = Store::open(test_dir.path().join("store.qualia"))?;
+
+    let checkpoint = store.checkpoint()?;
+    for (width, height) in [(4, 3), (3, 4), (4, 4)] {
+        checkpoint.add_with_id(&mut Photo {
+            object_id: None,
+            width,
+            height,
+        })?;
+    }
+    checkpoint.commit("add photos")?;
+
+    let wide: Vec<Photo> = store
+        .query(
+            Photo::q()
+                .equal("photo.w", 4)
+                .greater_than("photo.height", 3),
+        )
+        .iter_as()?
+        .collect();
+    assert_eq!(
+        wide,
+        [Photo {
+            object_id: Some(3),
+            width: 4,
+            height: 4,
+        }]
+    );
+    assert_eq!(store.query(Photo::query().height(4)).len()?, 2);
+
+    Ok(())
+}
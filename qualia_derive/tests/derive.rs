@@ -101,6 +101,43 @@ fn converting_fails_when_fields_wrong_type() -> Result<(), ConversionError> {
     Ok(())
 }
 
+#[test]
+fn wrong_type_errors_name_the_expected_and_actual_types() -> Result<(), ConversionError> {
+    assert_eq!(
+        Shape::try_from(object!("name" => 4, "width" => 8, "height" => 11))
+            .err()
+            .unwrap()
+            .to_string(),
+        "field name can't be converted to string, is number",
+    );
+
+    assert_eq!(
+        Shape::try_from(object!("name" => "letter", "width" => "potato", "height" => 11))
+            .err()
+            .unwrap()
+            .to_string(),
+        "field width can't be converted to number, is string",
+    );
+
+    assert_eq!(
+        Shape::try_from(object!("name" => "letter", "width" => vec![1u8], "height" => 11))
+            .err()
+            .unwrap()
+            .to_string(),
+        "field width can't be converted to number, is bytes",
+    );
+
+    assert_eq!(
+        ShapeWithId::try_from(object!("object_id" => "string", "name" => "letter", "width" => 8))
+            .err()
+            .unwrap()
+            .to_string(),
+        "field object_id can't be converted to number, is string",
+    );
+
+    Ok(())
+}
+
 #[test]
 fn can_convert_to_object() -> Result<(), ConversionError> {
     let obj: Object = Shape {
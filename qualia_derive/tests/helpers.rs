@@ -1,4 +1,7 @@
-use qualia::{object, ConversionError, ObjectShape, ObjectShapeWithId, Queryable, Result, Q};
+use qualia::{
+    object, ConversionError, Object, ObjectShape, ObjectShapeWithId, Queryable, Result, Store,
+    StoreError, Q,
+};
 
 #[test]
 fn returns_query_helper() -> Result<(), ConversionError> {
@@ -16,6 +19,213 @@ fn returns_query_helper() -> Result<(), ConversionError> {
     Ok(())
 }
 
+#[test]
+fn derives_query_helper_alone() -> Result<(), ConversionError> {
+    #[derive(Queryable)]
+    #[fixed_fields("kind" => "file", "version" => 2)]
+    struct Files;
+
+    assert_eq!(
+        Files::q().equal("name", "a").build(),
+        Q.equal("kind", "file")
+            .equal("version", 2)
+            .equal("name", "a")
+            .build()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn can_count_objects_by_shape() -> Result<(), StoreError> {
+    #[derive(Queryable)]
+    #[fixed_fields("kind" => "letter")]
+    struct Letters;
+
+    #[derive(Queryable)]
+    #[fixed_fields("kind" => "parcel")]
+    struct Parcels;
+
+    let test_dir = tempfile::tempdir().unwrap();
+    let mut store = Store::open(test_dir.path().join("store.qualia"))?;
+
+    let checkpoint = store.checkpoint()?;
+    checkpoint.add(object!("kind" => "letter", "to" => "a"))?;
+    checkpoint.add(object!("kind" => "letter", "to" => "b"))?;
+    checkpoint.add(object!("kind" => "parcel", "to" => "a"))?;
+    checkpoint.add(object!("kind" => "postcard", "to" => "c"))?;
+    checkpoint.commit("add mail")?;
+
+    assert_eq!(
+        store.counts_by_shape([Letters::q(), Parcels::q()])?,
+        vec![2, 1]
+    );
+    assert_eq!(
+        store.counts_by_shape([Letters::q().equal("to", "a"), Parcels::q().equal("to", "b")])?,
+        vec![1, 0]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn can_get_changed_fields() -> Result<(), ConversionError> {
+    #[derive(Clone, ObjectShape)]
+    #[fixed_fields("kind" => "shape")]
+    struct ChangedShape {
+        object_id: Option<i64>,
+        #[field("my-name")]
+        name: String,
+        width: i64,
+        height: Option<i64>,
+        #[rest_fields]
+        rest: Object,
+    }
+
+    let old = ChangedShape {
+        object_id: Some(1),
+        name: "letter".to_string(),
+        width: 8,
+        height: Some(11),
+        rest: object!("color" => "white", "weight" => 5),
+    };
+
+    assert_eq!(old.changed_fields(&old.clone()), object!());
+
+    let new = ChangedShape {
+        object_id: Some(2),
+        name: "legal".to_string(),
+        width: 8,
+        height: Some(14),
+        rest: object!("color" => "white", "weight" => 6, "folded" => 1),
+    };
+
+    assert_eq!(
+        old.changed_fields(&new),
+        object!("my-name" => "legal", "height" => 14, "weight" => 6, "folded" => 1)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn can_check_for_lost_properties() -> Result<(), ConversionError> {
+    #[derive(ObjectShape)]
+    #[fixed_fields("kind" => "shape")]
+    struct LossyShape {
+        #[field("my-name", alias = "name")]
+        name: String,
+        width: Option<i64>,
+    }
+
+    #[derive(ObjectShape)]
+    struct LosslessShape {
+        name: String,
+        #[rest_fields]
+        rest: Object,
+    }
+
+    assert!(!LossyShape::is_lossless());
+    assert!(LosslessShape::is_lossless());
+
+    let object = object!(
+        "object_id" => 1,
+        "kind" => "shape",
+        "name" => "square",
+        "width" => 5,
+        "color" => "red",
+        "area" => 25,
+    );
+
+    assert_eq!(LossyShape::would_lose(&object), vec!["area", "color"]);
+    assert_eq!(
+        LossyShape::would_lose(&object!("kind" => "shape")),
+        Vec::<String>::new()
+    );
+    assert_eq!(LosslessShape::would_lose(&object), Vec::<String>::new());
+
+    Ok(())
+}
+
+#[test]
+fn validates_all_fields_at_once() -> Result<(), ConversionError> {
+    #[derive(ObjectShape)]
+    #[fixed_fields("kind" => "shape")]
+    struct ValidatedShape {
+        #[field("my-name")]
+        name: String,
+        width: i64,
+        height: Option<i64>,
+    }
+
+    assert_eq!(
+        ValidatedShape::validate(&object!("kind" => "shape", "my-name" => "square", "width" => 5)),
+        Ok(())
+    );
+
+    assert_eq!(
+        ValidatedShape::validate(
+            &object!("kind" => "circle", "width" => "wide", "height" => "tall")
+        ),
+        Err(vec![
+            ConversionError::FixedFieldWrongValue(
+                "kind".to_string(),
+                "shape".into(),
+                "circle".into()
+            ),
+            ConversionError::FieldMissing("my-name".to_string()),
+            ConversionError::FieldWrongType(
+                "width".to_string(),
+                "number".to_string(),
+                "string".to_string()
+            ),
+            ConversionError::FieldWrongType(
+                "height".to_string(),
+                "number".to_string(),
+                "string".to_string()
+            ),
+        ])
+    );
+
+    Ok(())
+}
+
+#[test]
+fn can_convert_to_objects_without_annotations() -> Result<(), ConversionError> {
+    #[derive(ObjectShape)]
+    #[fixed_fields("kind" => "shape")]
+    struct ConvertedShape {
+        object_id: Option<i64>,
+        #[field("my-name")]
+        name: String,
+        width: i64,
+        height: Option<i64>,
+        #[rest_fields]
+        rest: Object,
+    }
+
+    let shape = ConvertedShape {
+        object_id: Some(3),
+        name: "letter".to_string(),
+        width: 8,
+        height: None,
+        rest: object!("color" => "white"),
+    };
+    let expected = object!(
+        "object_id" => 3,
+        "kind" => "shape",
+        "my-name" => "letter",
+        "width" => 8,
+        "color" => "white",
+    );
+
+    assert_eq!(shape.to_object(), expected);
+    assert_eq!(shape.name, "letter");
+    assert_eq!(shape.into_object(), expected);
+
+    Ok(())
+}
+
 #[test]
 fn can_get_and_set_id() -> Result<(), ConversionError> {
     #[derive(ObjectShape)]
@@ -33,3 +243,190 @@ fn can_get_and_set_id() -> Result<(), ConversionError> {
 
     Ok(())
 }
+
+#[test]
+fn generates_builders() -> Result<(), ConversionError> {
+    #[derive(Debug, ObjectShape, PartialEq)]
+    #[builder]
+    struct BuiltShape {
+        object_id: Option<i64>,
+        #[field("my-name")]
+        name: String,
+        #[builder(default = "plain")]
+        style: String,
+        #[builder(default)]
+        width: i64,
+        height: i64,
+        #[rest_fields]
+        rest: Object,
+    }
+
+    assert_eq!(
+        BuiltShape::builder().name("letter").height(11).build()?,
+        BuiltShape {
+            object_id: None,
+            name: "letter".to_string(),
+            style: "plain".to_string(),
+            width: 0,
+            height: 11,
+            rest: Object::new(),
+        }
+    );
+
+    assert_eq!(
+        BuiltShape::builder()
+            .object_id(4)
+            .name("letter")
+            .style("fancy")
+            .width(8)
+            .height(11)
+            .rest(object!("kind" => "paper"))
+            .build()?,
+        BuiltShape {
+            object_id: Some(4),
+            name: "letter".to_string(),
+            style: "fancy".to_string(),
+            width: 8,
+            height: 11,
+            rest: object!("kind" => "paper"),
+        }
+    );
+
+    assert_eq!(
+        BuiltShape::builder().height(11).build(),
+        Err(ConversionError::FieldMissing("my-name".to_string()))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn builders_are_opt_in() -> Result<(), ConversionError> {
+    // Without `#[builder]`, the names a builder would use are free.
+    #[derive(Debug, ObjectShape, PartialEq)]
+    struct PlainShape {
+        name: String,
+    }
+
+    struct PlainShapeBuilder;
+
+    impl PlainShape {
+        fn builder() -> PlainShapeBuilder {
+            PlainShapeBuilder
+        }
+    }
+
+    let PlainShapeBuilder = PlainShape::builder();
+
+    // Builder defaults don't clash with deriving `Default`.
+    #[derive(Debug, Default, ObjectShape, PartialEq)]
+    #[builder]
+    struct DefaultShape {
+        name: String,
+        #[builder(default = 8)]
+        width: i64,
+    }
+
+    assert_eq!(
+        DefaultShape::builder().name("letter").build()?,
+        DefaultShape {
+            name: "letter".to_string(),
+            width: 8,
+        }
+    );
+    assert_eq!(DefaultShape::default().width, 0);
+
+    Ok(())
+}
+
+#[test]
+fn generates_property_name_constants() -> Result<(), ConversionError> {
+    #[derive(ObjectShape)]
+    struct NamedShape {
+        object_id: Option<i64>,
+        #[field("shape-name")]
+        shape_name: String,
+        width: i64,
+    }
+
+    assert_eq!(NamedShape::OBJECT_ID_FIELD, "object_id");
+    assert_eq!(NamedShape::SHAPE_NAME_FIELD, "shape-name");
+    assert_eq!(NamedShape::WIDTH_FIELD, "width");
+
+    assert_eq!(
+        Q.equal(NamedShape::WIDTH_FIELD, 8).build(),
+        Q.equal("width", 8).build()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn generates_typed_queries() -> Result<(), ConversionError> {
+    #[derive(ObjectShape)]
+    #[fixed_fields("kind" => "typed")]
+    #[typed_query]
+    struct TypedShape {
+        #[field("my-name")]
+        name: String,
+        width: i64,
+    }
+
+    assert_eq!(
+        TypedShape::query().build(),
+        Q.equal("kind", "typed").build()
+    );
+
+    assert_eq!(
+        TypedShape::query().width(8).name("letter").build(),
+        Q.equal("kind", "typed")
+            .equal("width", 8)
+            .equal("my-name", "letter")
+            .build()
+    );
+
+    assert_eq!(
+        TypedShape::query()
+            .width_gt(8)
+            .width_lt(11)
+            .width_between(9, 10)
+            .build(),
+        Q.equal("kind", "typed")
+            .greater_than("width", 8)
+            .less_than("width", 11)
+            .between("width", 9, 10)
+            .build()
+    );
+
+    assert_eq!(
+        TypedShape::query()
+            .name_like("let*")
+            .name_starts_with("le")
+            .build(),
+        Q.equal("kind", "typed")
+            .like("my-name", "let*")
+            .starts_with("my-name", "le")
+            .build()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn typed_queries_are_opt_in() {
+    // Without `#[typed_query]`, the names a typed query would use are free.
+    #[derive(ObjectShape)]
+    struct PlainShape {
+        name: String,
+    }
+
+    struct PlainShapeQuery;
+
+    impl PlainShape {
+        fn query() -> PlainShapeQuery {
+            PlainShapeQuery
+        }
+    }
+
+    let PlainShapeQuery = PlainShape::query();
+}
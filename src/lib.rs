@@ -1,6 +1,8 @@
 //! A document store with a flexible query language and built-in undo support.
 
 pub mod object;
+#[cfg(feature = "r2d2")]
+pub mod pool;
 pub mod query;
 pub mod query_builder;
 pub mod store;
@@ -10,6 +12,13 @@ pub use object::*;
 #[doc(inline)]
 pub use qualia_derive::ObjectShape;
 #[doc(inline)]
+pub use qualia_derive::PropEnum;
+#[doc(inline)]
+pub use qualia_derive::Queryable;
+#[doc(inline)]
 pub use query_builder::Q;
 #[doc(inline)]
 pub use store::*;
+
+#[doc(hidden)]
+pub use serde_json;
@@ -1,16 +1,155 @@
 #![macro_use]
+use serde::de::{Error as _, Unexpected};
+use serde::ser::SerializeMap;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
-use crate::{query_builder::QueryBuilder, Store, StoreError};
+use crate::{query::QueryNode, query_builder::QueryBuilder, Store, StoreError};
 
 /// All possible types that can be stored inside an [`Object`].
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(untagged)]
+///
+/// As JSON can't hold raw bytes, [`Bytes`](PropValue::Bytes) are stored as an object with a single
+/// `base64` property holding the base64-encoded bytes, such as `{"base64": "AAEC"}`. They can be
+/// compared with [`Q.equal()`](crate::query_builder::QueryBuilder::equal) and
+/// [`Q.any_of()`](crate::query_builder::QueryBuilder::any_of), but not ordered or searched.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum PropValue {
     Number(i64),
     String(String),
+    Bytes(Vec<u8>),
+}
+
+/// The property of the JSON object that [`PropValue::Bytes`] are stored in.
+const BYTES_KEY: &str = "base64";
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encode bytes as standard, padded base64.
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| {
+            group | (byte as u32) << (16 - 8 * i)
+        });
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+/// Decode standard, padded base64, describing the problem if it is malformed.
+fn base64_decode(encoded: &str) -> Result<Vec<u8>, String> {
+    if encoded.len() % 4 != 0 {
+        return Err(format!(
+            "base64 length must be a multiple of 4, is {}",
+            encoded.len()
+        ));
+    }
+
+    let mut bytes = Vec::with_capacity(encoded.len() / 4 * 3);
+    let chunk_count = encoded.len() / 4;
+
+    for (chunk_index, chunk) in encoded.as_bytes().chunks(4).enumerate() {
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && chunk_index != chunk_count - 1) {
+            return Err("base64 padding is only allowed at the end".to_string());
+        }
+
+        let mut group = 0u32;
+        for (i, &c) in chunk[..4 - padding].iter().enumerate() {
+            let value = BASE64_ALPHABET
+                .iter()
+                .position(|&a| a == c)
+                .ok_or_else(|| format!("invalid base64 character {:?}", c as char))?;
+            group |= (value as u32) << (18 - 6 * i);
+        }
+
+        bytes.extend_from_slice(&group.to_be_bytes()[1..4 - padding]);
+    }
+
+    Ok(bytes)
+}
+
+impl Serialize for PropValue {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            PropValue::Number(n) => serializer.serialize_i64(*n),
+            PropValue::String(s) => serializer.serialize_str(s),
+            PropValue::Bytes(b) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry(BYTES_KEY, &base64_encode(b))?;
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PropValue {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PropValueVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for PropValueVisitor {
+            type Value = PropValue;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(
+                    f,
+                    "an integer, a string or an object with only a {:?} string",
+                    BYTES_KEY
+                )
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, n: i64) -> Result<PropValue, E> {
+                Ok(PropValue::Number(n))
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, n: u64) -> Result<PropValue, E> {
+                i64::try_from(n)
+                    .map(PropValue::Number)
+                    .map_err(|_| E::invalid_value(Unexpected::Unsigned(n), &self))
+            }
+
+            fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<PropValue, E> {
+                Ok(PropValue::String(s.to_string()))
+            }
+
+            fn visit_string<E: serde::de::Error>(self, s: String) -> Result<PropValue, E> {
+                Ok(PropValue::String(s))
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> Result<PropValue, A::Error> {
+                let encoded = match map.next_entry::<String, String>()? {
+                    Some((key, encoded)) if key == BYTES_KEY => encoded,
+                    _ => return Err(A::Error::invalid_value(Unexpected::Map, &self)),
+                };
+
+                if map.next_key::<serde::de::IgnoredAny>()?.is_some() {
+                    return Err(A::Error::invalid_value(Unexpected::Map, &self));
+                }
+
+                base64_decode(&encoded)
+                    .map(PropValue::Bytes)
+                    .map_err(A::Error::custom)
+            }
+        }
+
+        deserializer.deserialize_any(PropValueVisitor)
+    }
 }
 
 /// A set of properties that may be stored in a [`Store`](crate::Store).
@@ -24,12 +163,32 @@ pub enum ConversionError {
     FieldMissing(String),
 
     // Returned when a field can't be converted to the necessary type.
-    #[error("field {0} can't be converted to {1}")]
-    FieldWrongType(String, String),
+    //
+    // Holds the name of the field, the type it should be, and the type it is.
+    #[error("field {0} can't be converted to {1}, is {2}")]
+    FieldWrongType(String, String, String),
+
+    // Returned when a property value can't be converted to the necessary type.
+    //
+    // Holds the type it should be, and the type it is.
+    #[error("value can't be converted to {0}, is {1}")]
+    ValueWrongType(String, String),
+
+    // Returned when a number of seconds since the Unix epoch is too large to be a `SystemTime`.
+    #[error("timestamp {0} is out of range")]
+    TimestampOutOfRange(i64),
 
     // Returned when a field can't be converted to the necessary type.
     #[error("fixed field {0} should be {1:?}, is {2:?}")]
     FixedFieldWrongValue(String, PropValue, PropValue),
+
+    // Returned when a JSON value that is not an object is converted to an object.
+    #[error("JSON value is not an object")]
+    NotAnObject,
+
+    // Returned when a JSON value can't be stored in a property.
+    #[error("JSON value {0} can't be converted to number or string")]
+    InvalidJsonValue(serde_json::Value),
 }
 
 pub trait Queryable {
@@ -37,8 +196,54 @@ pub trait Queryable {
     fn q() -> QueryBuilder;
 }
 
+/// The kind of value stored in a property of an [`ObjectShape`], as listed in
+/// [`ObjectShape::PROPERTIES`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PropKind {
+    /// A number, from an `i64` field.
+    Number,
+
+    /// A number that may be missing, from an `Option<i64>` field.
+    OptionalNumber,
+
+    /// A string, from a `String` field.
+    String,
+
+    /// One of the given strings, from a `#[prop_enum]` field.
+    Enum(&'static [&'static str]),
+
+    /// A JSON-serialized string, from a `#[json]` field.
+    Json,
+
+    /// A number stored as a string, from a `#[bignum]` field.
+    BigNum,
+
+    /// The ID of a referenced object, from an object field.
+    Object,
+}
+
 /// A type that can be converted to and from an object.
 pub trait ObjectShape: Queryable + std::convert::Into<Object> {
+    /// Whether converting to this shape reads the `object_id` property.
+    ///
+    /// When this is false, [`Collection::iter_as()`](crate::Collection::iter_as) and friends skip
+    /// adding `object_id` to each object before converting it. Derived shapes set this to false
+    /// when they have neither an `object_id` field nor a `#[rest_fields]` field.
+    const USES_OBJECT_ID: bool = true;
+
+    /// Properties that no two objects of this shape may share a value for.
+    ///
+    /// This is checked by [`Checkpoint::add_with_id()`](crate::Checkpoint::add_with_id), which
+    /// only compares against objects matching [`q()`](Queryable::q). Derived shapes list the
+    /// fields marked with `#[unique]`.
+    const UNIQUE_FIELDS: &'static [&'static str] = &[];
+
+    /// The properties stored for this shape, and the kind of value each holds.
+    ///
+    /// Derived shapes list each field other than the `object_id` and `#[rest_fields]` fields, in
+    /// order, under the property they are stored in. Fixed fields are not included.
+    const PROPERTIES: &'static [(&'static str, PropKind)] = &[];
+
     /// Try to convert the given object into this shape, retrieving any referenced objects from the
     /// given store.
     fn try_convert(object: Object, store: &Store) -> Result<Self, StoreError>;
@@ -53,7 +258,26 @@ pub trait ObjectShapePlain:
 {
 }
 
+/// A field-less enum that can be stored as a string property.
+///
+/// This should usually be derived with [`PropEnum`](qualia_derive::PropEnum).
+pub trait PropEnum: Sized {
+    /// The property values of all variants.
+    const VARIANTS: &'static [&'static str];
+
+    /// Get the variant with the given property value, if any.
+    fn from_prop_str(s: &str) -> Option<Self>;
+
+    /// Get the property value of this variant.
+    fn to_prop_str(&self) -> &'static str;
+}
+
 /// A type that can be converted to and from an object, with its `object_id`.
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` must derive `ObjectShape` with an `object_id` field to be used as a related object",
+    label = "`{Self}` has no `object_id` field",
+    note = "add an `object_id: Option<i64>` field to `{Self}` and derive `ObjectShape` on it"
+)]
 pub trait ObjectShapeWithId: ObjectShape {
     /// Get the object's ID.
     fn get_object_id(&self) -> Option<i64>;
@@ -62,6 +286,60 @@ pub trait ObjectShapeWithId: ObjectShape {
     fn set_object_id(&mut self, object_id: i64);
 }
 
+/// An object sorted by a [`ShapeRegistry`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Classified<T> {
+    /// The object matched a registered shape, and was converted to it.
+    Shape(T),
+
+    /// The object didn't match any registered shape.
+    Other(Object),
+}
+
+/// A set of object shapes to sort objects into, usually told apart by their fixed fields.
+///
+/// Each shape is converted into a common type `T`, usually an enum with a variant per shape. See
+/// [`Collection::iter_classified()`](crate::Collection::iter_classified).
+pub struct ShapeRegistry<T> {
+    pub(crate) shapes: Vec<RegisteredShape<T>>,
+}
+
+pub(crate) struct RegisteredShape<T> {
+    pub(crate) query: QueryNode,
+    pub(crate) uses_object_id: bool,
+    pub(crate) convert: Box<dyn Fn(Object) -> Result<T, ConversionError>>,
+}
+
+impl<T> ShapeRegistry<T> {
+    /// Create a registry without any shapes.
+    pub fn new() -> Self {
+        ShapeRegistry { shapes: Vec::new() }
+    }
+
+    /// Add a shape, wrapping converted objects with `wrap`.
+    ///
+    /// Objects matching the shape's [`q()`](Queryable::q) are converted to it. When an object
+    /// matches more than one shape, the first registered one is used.
+    pub fn register<S: ObjectShapePlain + 'static>(
+        mut self,
+        wrap: impl Fn(S) -> T + 'static,
+    ) -> Self {
+        self.shapes.push(RegisteredShape {
+            query: S::q().build(),
+            uses_object_id: S::USES_OBJECT_ID,
+            convert: Box::new(move |object| S::try_from(object).map(&wrap)),
+        });
+
+        self
+    }
+}
+
+impl<T> Default for ShapeRegistry<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Convenience macro for creating an [`Object`].
 #[macro_export]
 macro_rules! object {
@@ -75,6 +353,30 @@ macro_rules! object {
     };
 }
 
+/// Convenience macro for defining an [`Object`] in a `static`, built the first time it is used.
+///
+/// Takes the same arguments as [`object!`], and expands to a
+/// [`LazyLock`](std::sync::LazyLock), which can be dereferenced to get the object.
+///
+/// ```
+/// # use qualia::{static_object, Object};
+/// # use std::sync::LazyLock;
+/// static DEFAULT_FOLDER: LazyLock<Object> = static_object!("kind" => "folder", "name" => "Inbox");
+///
+/// assert_eq!(DEFAULT_FOLDER["name"], "Inbox".into());
+/// ```
+#[macro_export]
+macro_rules! static_object {
+    ( $($key:expr => $value:expr $(,)?)* ) => {
+        std::sync::LazyLock::new(|| {
+            #[allow(unused_mut)]
+            let mut object = $crate::Object::new();
+            $(object.insert($key.into(), $value.into());)*
+            object
+        })
+    };
+}
+
 impl PropValue {
     /// If this [`PropValue`] contains a [`String`], return it. If not, return [`None`].
     pub fn as_str(&self) -> Option<&String> {
@@ -84,6 +386,14 @@ impl PropValue {
         }
     }
 
+    /// If this [`PropValue`] contains bytes, return them. If not, return [`None`].
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            PropValue::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
     /// If this [`PropValue`] contains an [`i64`], return it. If not, return [`None`].
     pub fn as_number(&self) -> Option<i64> {
         match self {
@@ -91,23 +401,122 @@ impl PropValue {
             _ => None,
         }
     }
+
+    /// The name of the type of this [`PropValue`], as used in error messages: `"number"`,
+    /// `"string"` or `"bytes"`.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            PropValue::Number(_) => "number",
+            PropValue::String(_) => "string",
+            PropValue::Bytes(_) => "bytes",
+        }
+    }
+
+    /// Render this [`PropValue`] as compact JSON, such as `"blah"` or `42`.
+    pub fn to_json_string(&self) -> String {
+        serde_json::Value::from(self).to_string()
+    }
+
+    /// Render this [`PropValue`] as indented JSON.
+    ///
+    /// Bytes are rendered over several lines, as an object with a `base64` property.
+    pub fn to_json_string_pretty(&self) -> String {
+        format!("{:#}", serde_json::Value::from(self))
+    }
+}
+
+/// Convert a JSON object, such as one parsed from a request body, into an [`Object`].
+///
+/// Returns [`ConversionError::NotAnObject`] if `value` is not an object, and
+/// [`ConversionError::FieldWrongType`] if any property is not a string or an integer that fits in
+/// an [`i64`].
+pub fn object_from_json(value: serde_json::Value) -> Result<Object, ConversionError> {
+    match value {
+        serde_json::Value::Object(map) => object_from_json_map(map),
+        _ => Err(ConversionError::NotAnObject),
+    }
+}
+
+/// Convert a [`serde_json::Map`] into an [`Object`].
+///
+/// Returns [`ConversionError::FieldWrongType`] if any property is not a string or an integer that
+/// fits in an [`i64`].
+pub fn object_from_json_map(
+    map: serde_json::Map<String, serde_json::Value>,
+) -> Result<Object, ConversionError> {
+    map.into_iter()
+        .map(|(name, value)| match PropValue::try_from(value) {
+            Ok(prop_value) => Ok((name, prop_value)),
+            Err(ConversionError::InvalidJsonValue(value)) => Err(ConversionError::FieldWrongType(
+                name,
+                "number or string".to_string(),
+                json_type_name(&value).to_string(),
+            )),
+            Err(e) => Err(e),
+        })
+        .collect()
+}
+
+/// The name of the type of a JSON value that can't be converted to a [`PropValue`], for
+/// [`ConversionError::FieldWrongType`].
+fn json_type_name(value: &serde_json::Value) -> &'static str {
+    match value {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "bool",
+        serde_json::Value::Number(n) if n.is_f64() => "float",
+        serde_json::Value::Number(_) => "out-of-range integer",
+        serde_json::Value::String(_) => "string",
+        serde_json::Value::Array(_) => "array",
+        serde_json::Value::Object(_) => "object",
+    }
+}
+
+/// Convert an [`Object`] into a [`serde_json::Map`].
+pub fn object_to_json_map(object: &Object) -> serde_json::Map<String, serde_json::Value> {
+    object
+        .iter()
+        .map(|(name, value)| (name.clone(), value.into()))
+        .collect()
 }
 
-impl From<serde_json::Value> for PropValue {
-    fn from(x: serde_json::Value) -> Self {
+/// Convert a JSON string, number or base64 bytes object into a [`PropValue`].
+///
+/// Returns [`ConversionError::InvalidJsonValue`] if the value is not a string, an integer that
+/// fits in an [`i64`] or an object with only a `base64` property holding valid base64.
+impl TryFrom<serde_json::Value> for PropValue {
+    type Error = ConversionError;
+
+    fn try_from(x: serde_json::Value) -> Result<Self, Self::Error> {
         match x {
-            serde_json::Value::String(s) => PropValue::String(s),
-            serde_json::Value::Number(n) => PropValue::Number(n.as_i64().unwrap()),
-            _ => {
-                panic!("attempt to create PropValue from serde_json::Value not a Number or String")
+            serde_json::Value::String(s) => Ok(PropValue::String(s)),
+            serde_json::Value::Number(n) if n.is_i64() => {
+                Ok(PropValue::Number(n.as_i64().unwrap()))
             }
+            serde_json::Value::Object(_) => {
+                serde_json::from_value(x.clone()).map_err(|_| ConversionError::InvalidJsonValue(x))
+            }
+            x => Err(ConversionError::InvalidJsonValue(x)),
         }
     }
 }
 
-impl From<&serde_json::Value> for PropValue {
-    fn from(x: &serde_json::Value) -> Self {
-        PropValue::from(x.clone())
+impl TryFrom<&serde_json::Value> for PropValue {
+    type Error = ConversionError;
+
+    fn try_from(x: &serde_json::Value) -> Result<Self, Self::Error> {
+        PropValue::try_from(x.clone())
+    }
+}
+
+impl From<&PropValue> for serde_json::Value {
+    fn from(x: &PropValue) -> Self {
+        match x {
+            PropValue::Number(n) => serde_json::Value::from(*n),
+            PropValue::String(s) => serde_json::Value::from(s.as_str()),
+            PropValue::Bytes(b) => {
+                serde_json::json!({ BYTES_KEY: base64_encode(b) })
+            }
+        }
     }
 }
 
@@ -129,8 +538,243 @@ impl From<&String> for PropValue {
     }
 }
 
+impl From<Vec<u8>> for PropValue {
+    fn from(b: Vec<u8>) -> Self {
+        PropValue::Bytes(b)
+    }
+}
+
+impl From<&[u8]> for PropValue {
+    fn from(b: &[u8]) -> Self {
+        PropValue::Bytes(b.to_vec())
+    }
+}
+
 impl From<i64> for PropValue {
     fn from(s: i64) -> Self {
         PropValue::Number(s.clone())
     }
 }
+
+/// Times are stored as a [`Number`](PropValue::Number) of whole seconds since the Unix epoch,
+/// rounded down. Times before the epoch are stored as negative numbers, so one and a half seconds
+/// before the epoch is stored as -2.
+impl From<SystemTime> for PropValue {
+    fn from(time: SystemTime) -> Self {
+        let seconds = match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => i64::try_from(since.as_secs()).unwrap_or(i64::MAX),
+            Err(e) => {
+                let before = e.duration();
+                let seconds = before.as_secs() + u64::from(before.subsec_nanos() > 0);
+
+                i64::try_from(seconds).map_or(i64::MIN, |seconds| -seconds)
+            }
+        };
+
+        PropValue::Number(seconds)
+    }
+}
+
+impl TryFrom<&PropValue> for SystemTime {
+    type Error = ConversionError;
+
+    fn try_from(value: &PropValue) -> Result<Self, Self::Error> {
+        let seconds = value.as_number().ok_or_else(|| {
+            ConversionError::ValueWrongType("number".to_string(), value.type_name().to_string())
+        })?;
+        let offset = Duration::from_secs(seconds.unsigned_abs());
+
+        if seconds >= 0 {
+            UNIX_EPOCH.checked_add(offset)
+        } else {
+            UNIX_EPOCH.checked_sub(offset)
+        }
+        .ok_or(ConversionError::TimestampOutOfRange(seconds))
+    }
+}
+
+impl TryFrom<PropValue> for SystemTime {
+    type Error = ConversionError;
+
+    fn try_from(value: PropValue) -> Result<Self, Self::Error> {
+        SystemTime::try_from(&value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn prop_values_can_be_rendered_as_json() {
+        let string = PropValue::from("say \"hi\"\n");
+        assert_eq!(string.to_json_string(), r#""say \"hi\"\n""#);
+        assert_eq!(string.to_json_string_pretty(), r#""say \"hi\"\n""#);
+
+        let number = PropValue::from(-42);
+        assert_eq!(number.to_json_string(), "-42");
+        assert_eq!(number.to_json_string_pretty(), "-42");
+    }
+
+    #[test]
+    fn prop_values_can_be_converted_from_json() {
+        assert_eq!(
+            PropValue::try_from(json!("one")),
+            Ok(PropValue::String("one".to_string()))
+        );
+        assert_eq!(PropValue::try_from(&json!(5)), Ok(PropValue::Number(5)));
+    }
+
+    #[test]
+    fn prop_values_have_type_names() {
+        assert_eq!(PropValue::Number(1).type_name(), "number");
+        assert_eq!(PropValue::String("one".to_string()).type_name(), "string");
+        assert_eq!(PropValue::Bytes(vec![1]).type_name(), "bytes");
+    }
+
+    #[test]
+    fn system_times_round_trip_through_prop_values() {
+        for seconds in [0i64, 1, 1_700_000_000, -1, -86_400] {
+            let time = if seconds >= 0 {
+                UNIX_EPOCH + Duration::from_secs(seconds as u64)
+            } else {
+                UNIX_EPOCH - Duration::from_secs(seconds.unsigned_abs())
+            };
+            let value = PropValue::from(time);
+
+            assert_eq!(value, PropValue::Number(seconds));
+            assert_eq!(SystemTime::try_from(value), Ok(time));
+        }
+
+        assert_eq!(
+            PropValue::from(UNIX_EPOCH + Duration::from_millis(1500)),
+            PropValue::Number(1)
+        );
+        assert_eq!(
+            PropValue::from(UNIX_EPOCH - Duration::from_millis(1500)),
+            PropValue::Number(-2)
+        );
+
+        assert_eq!(
+            SystemTime::try_from(PropValue::String("now".to_string())),
+            Err(ConversionError::ValueWrongType(
+                "number".to_string(),
+                "string".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn bytes_are_serialized_as_base64() {
+        for (bytes, encoded) in [
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg=="),
+            (&[0, 255, 128, 7][..], "AP+ABw=="),
+        ] {
+            let value = PropValue::from(bytes);
+            let serialized = serde_json::to_string(&value).unwrap();
+
+            assert_eq!(serialized, format!(r#"{{"base64":"{}"}}"#, encoded));
+            assert_eq!(
+                serde_json::from_str::<PropValue>(&serialized).unwrap(),
+                value
+            );
+            assert_eq!(PropValue::try_from(json!({ "base64": encoded })), Ok(value));
+        }
+
+        let object = object!("name" => "thumbnail", "data" => vec![1u8, 2, 3]);
+        let serialized = serde_json::to_string(&object).unwrap();
+        assert_eq!(serde_json::from_str::<Object>(&serialized).unwrap(), object);
+        assert_eq!(object["data"].as_bytes(), Some(&[1, 2, 3][..]));
+        assert_eq!(object["name"].as_bytes(), None);
+    }
+
+    #[test]
+    fn malformed_base64_is_rejected() {
+        for (encoded, message) in [
+            ("Zm9", "base64 length must be a multiple of 4, is 3"),
+            ("Zm9v!A==", "invalid base64 character '!'"),
+            ("Zg==Zm9v", "base64 padding is only allowed at the end"),
+            ("Z===", "base64 padding is only allowed at the end"),
+        ] {
+            let error = serde_json::from_value::<PropValue>(json!({ "base64": encoded }))
+                .unwrap_err()
+                .to_string();
+            assert_eq!(error, message);
+        }
+
+        assert!(serde_json::from_value::<PropValue>(json!({"base64": "Zg==", "a": 1})).is_err());
+        assert!(serde_json::from_value::<PropValue>(json!({"base32": "Zg=="})).is_err());
+    }
+
+    #[test]
+    fn invalid_json_prop_values_are_rejected() {
+        for value in [
+            json!(null),
+            json!(true),
+            json!(1.5),
+            json!(u64::MAX),
+            json!([1]),
+            json!({"a": 1}),
+        ] {
+            assert_eq!(
+                PropValue::try_from(&value),
+                Err(ConversionError::InvalidJsonValue(value)),
+            );
+        }
+    }
+
+    #[test]
+    fn objects_can_be_converted_from_json() {
+        assert_eq!(
+            object_from_json(json!({"name": "one", "width": 5})),
+            Ok(object!("name" => "one", "width" => 5)),
+        );
+    }
+
+    #[test]
+    fn objects_can_be_converted_to_and_from_json_maps() {
+        let object = object!("name" => "one", "width" => 5);
+        let map = match json!({"name": "one", "width": 5}) {
+            serde_json::Value::Object(map) => map,
+            _ => unreachable!(),
+        };
+
+        assert_eq!(object_to_json_map(&object), map);
+        assert_eq!(object_from_json_map(map), Ok(object));
+        assert_eq!(
+            object_from_json_map(serde_json::Map::new()),
+            Ok(Object::new())
+        );
+    }
+
+    #[test]
+    fn invalid_json_objects_are_rejected() {
+        assert_eq!(
+            object_from_json(json!(["name", "one"])),
+            Err(ConversionError::NotAnObject),
+        );
+
+        for (value, type_name) in [
+            (json!(null), "null"),
+            (json!(true), "bool"),
+            (json!(1.5), "float"),
+            (json!(u64::MAX), "out-of-range integer"),
+            (json!([1]), "array"),
+            (json!({"a": 1}), "object"),
+        ] {
+            assert_eq!(
+                object_from_json(json!({"name": "one", "bad": value})),
+                Err(ConversionError::FieldWrongType(
+                    "bad".to_string(),
+                    "number or string".to_string(),
+                    type_name.to_string(),
+                )),
+            );
+        }
+    }
+}
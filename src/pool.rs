@@ -0,0 +1,67 @@
+//! Integration with the [`r2d2`] connection pool.
+//!
+//! Each pooled connection is a full [`Store`], opened (and upgraded, if needed) when the pool
+//! creates it. Note that pooling does nothing to serialize writes: SQLite will still only allow one
+//! writer at a time, and it's up to the caller to avoid or retry on conflicting checkpoints.
+
+use std::path::{Path, PathBuf};
+
+use crate::{Store, StoreError};
+
+/// An [`r2d2::ManageConnection`] that opens [`Store`]s at a given path.
+#[derive(Debug)]
+pub struct StoreManager {
+    path: PathBuf,
+}
+
+impl StoreManager {
+    /// Create a manager that will open stores at the given path.
+    pub fn new(path: impl AsRef<Path>) -> StoreManager {
+        StoreManager {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl r2d2::ManageConnection for StoreManager {
+    type Connection = Store;
+    type Error = StoreError;
+
+    fn connect(&self) -> Result<Store, StoreError> {
+        Store::open(&self.path)
+    }
+
+    fn is_valid(&self, store: &mut Store) -> Result<(), StoreError> {
+        store.conn.execute_batch("").map_err(StoreError::from)
+    }
+
+    fn has_broken(&self, _store: &mut Store) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Object, Q};
+    use tempfile::Builder;
+
+    #[test]
+    fn stores_can_be_pooled() -> crate::Result<()> {
+        let test_dir = Builder::new().prefix("qualia-pool").tempdir().unwrap();
+        let pool = r2d2::Pool::builder()
+            .max_size(2)
+            .build(StoreManager::new(test_dir.path().join("store.qualia")))
+            .unwrap();
+
+        let mut writer = pool.get().unwrap();
+        let checkpoint = writer.checkpoint()?;
+        checkpoint.add(object!("name" => "one"))?;
+        checkpoint.commit("add object")?;
+
+        let reader = pool.get().unwrap();
+        assert_eq!(reader.query(Q.equal("name", "one")).len()?, 1);
+
+        Ok(())
+    }
+}
@@ -1,4 +1,6 @@
+use rusqlite::types::ToSqlOutput;
 use rusqlite::ToSql;
+use thiserror::Error;
 
 use crate::object::PropValue;
 
@@ -14,6 +16,16 @@ pub enum QueryNode {
     /// Will match objects that have the given property with exactly the given value.
     PropEqual { name: String, value: PropValue },
 
+    /// Will match objects where the given property has the same text form as the given value,
+    /// whether it is stored as a number or a string.
+    ///
+    /// Numbers are compared by their text form, so the value `2021` will match both the property
+    /// values `2021` and `"2021"`, but not `"02021"` or `"2021 AD"`. This compares the same
+    /// expression as [`PropEqual`](QueryNode::PropEqual) with a string value, so it can use the
+    /// text half of an index from [`Store::create_index()`](crate::Store::create_index), but not the
+    /// number half; on `object_id`, it can't use an index at all.
+    PropEqualCoerced { name: String, value: PropValue },
+
     /// Will match objects that the given property with contents matching the given pattern.
     ///
     /// The pattern is composed of a set of words, each one of which must exist in order (though
@@ -33,10 +45,119 @@ pub enum QueryNode {
     ///   * `lucky why`
     ///   * `matts`
     ///   * `wha*`
+    ///
+    /// Numbers are matched by their text form, so `20*` will match the property value `2021`.
     PropLike { name: String, pattern: String },
 
+    /// Will match objects where the given property contains the given words next to each other.
+    ///
+    /// Each word is matched as in [`PropLike`](QueryNode::PropLike), but only whitespace may come
+    /// between them. For example, `lucky stiff` and `luck* stiff` will match the property value
+    /// `"why the lucky stiff"`, while `why lucky` and `stiff lucky` will not.
+    PropPhrase { name: String, phrase: String },
+
+    /// Will match objects where the given property matches the given regular expression.
+    ///
+    /// The regular expression uses the syntax of the [`regex`](https://docs.rs/regex) crate, and is
+    /// not anchored or made case-insensitive.
+    PropMatches { name: String, regex: String },
+
+    /// Will match objects where the given property compares to the given value as specified.
+    ///
+    /// Numbers are compared numerically and strings lexicographically, depending on the type of
+    /// `value`.
+    PropCompare {
+        name: String,
+        comparison: Comparison,
+        value: PropValue,
+    },
+
+    /// Will match objects where the given property is between `low` and `high`, inclusive.
+    PropBetween {
+        name: String,
+        low: PropValue,
+        high: PropValue,
+    },
+
+    /// Will match objects where the given property compares to the `other` property as specified.
+    ///
+    /// Both properties are compared as numbers. Objects missing either property never match, even
+    /// inside a [`Not`](QueryNode::Not).
+    PropCompareProp {
+        name: String,
+        comparison: Comparison,
+        other: String,
+    },
+
+    /// Will match objects where the given property starts with the given prefix.
+    ///
+    /// Unlike [`PropLike`](QueryNode::PropLike), the prefix is matched literally and
+    /// case-sensitively against the start of the property value. As with
+    /// [`PropLike`](QueryNode::PropLike), numbers are matched by their text form.
+    PropStartsWith { name: String, prefix: String },
+
+    /// Will match objects where the given property is equal to any of the given values.
+    ///
+    /// The values are bound as a single parameter, so any number of values may be given without
+    /// running into SQLite's limit on bound parameters.
+    PropIn {
+        name: String,
+        values: Vec<PropValue>,
+    },
+
+    /// Will match objects that have the given property, whatever its value.
+    PropExists { name: String },
+
+    /// Will match objects where the given property is equal to the `field` property of any object
+    /// matching `query`.
+    ///
+    /// Values are compared as stored, so a number will not match a string containing the same
+    /// digits. `field` may be `object_id`, to match objects referring to others by ID.
+    PropInQuery {
+        name: String,
+        query: Box<QueryNode>,
+        field: String,
+    },
+
     /// Will match all objects that match each of the contained criteria.
     And(Vec<QueryNode>),
+
+    /// Will match all objects that match any of the contained criteria.
+    ///
+    /// An empty `Or` matches no objects.
+    Or(Vec<QueryNode>),
+
+    /// Will match all objects that do not match the contained criteria.
+    Not(Box<QueryNode>),
+}
+
+/// A comparison used in a [`QueryNode::PropCompare`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    fn to_sql_operator(self) -> &'static str {
+        match self {
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Greater => ">",
+            Comparison::GreaterOrEqual => ">=",
+        }
+    }
+}
+
+/// Errors returned while building a query.
+#[derive(Error, Debug, PartialEq)]
+pub enum QueryError {
+    /// Returned by [`QueryBuilder::build_for()`](crate::query_builder::QueryBuilder::build_for)
+    /// when the query uses a property that the shape doesn't have.
+    #[error("{shape} has no property {name:?}")]
+    UnknownProperty { name: String, shape: String },
 }
 
 macro_rules! vec_params {
@@ -50,41 +171,430 @@ impl ToSql for PropValue {
         match self {
             PropValue::Number(n) => n.to_sql(),
             PropValue::String(s) => s.to_sql(),
+            // Bytes are compared by the JSON object they are stored as.
+            PropValue::Bytes(_) => Ok(ToSqlOutput::from(self.to_json_string())),
         }
     }
 }
 
 impl QueryNode {
-    pub(crate) fn to_sql_clause(&self) -> (String, Vec<Box<dyn ToSql>>) {
+    /// Convert this node to an SQL expression and its parameters, for a query on the given table.
+    ///
+    /// The table is only used by [`PropInQuery`](QueryNode::PropInQuery), which queries the same
+    /// table again.
+    pub(crate) fn to_sql_clause(&self, table: &str) -> (String, Vec<Box<dyn ToSql>>) {
         match self {
             QueryNode::Empty => ("1=1".to_string(), vec_params![]),
             QueryNode::PropEqual { name, value } => Self::equal_to_sql_clause(name, value),
-            QueryNode::PropLike { name, pattern } => Self::like_to_sql_clause(name, pattern),
-            QueryNode::And(nodes) => Self::and_to_sql_clause(nodes),
+            QueryNode::PropEqualCoerced { name, value } => {
+                Self::equal_coerced_to_sql_clause(name, value)
+            }
+            QueryNode::PropLike { name, pattern } => {
+                Self::words_to_sql_clause(name, pattern, r".*?")
+            }
+            QueryNode::PropPhrase { name, phrase } => {
+                Self::words_to_sql_clause(name, phrase, r"\s+")
+            }
+            QueryNode::PropMatches { name, regex } => Self::matches_to_sql_clause(name, regex),
+            QueryNode::PropCompare {
+                name,
+                comparison,
+                value,
+            } => Self::compare_to_sql_clause(name, *comparison, value),
+            QueryNode::PropBetween { name, low, high } => {
+                Self::between_to_sql_clause(name, low, high)
+            }
+            QueryNode::PropCompareProp {
+                name,
+                comparison,
+                other,
+            } => Self::compare_prop_to_sql_clause(name, *comparison, other),
+            QueryNode::PropStartsWith { name, prefix } => {
+                Self::starts_with_to_sql_clause(name, prefix)
+            }
+            QueryNode::PropIn { name, values } => Self::in_to_sql_clause(name, values),
+            QueryNode::PropExists { name } => Self::exists_to_sql_clause(name),
+            QueryNode::PropInQuery { name, query, field } => {
+                Self::in_query_to_sql_clause(table, name, query, field)
+            }
+            QueryNode::And(nodes) => Self::and_to_sql_clause(table, nodes),
+            QueryNode::Or(nodes) => Self::or_to_sql_clause(table, nodes),
+            QueryNode::Not(node) => {
+                let (clause, params) = node.to_sql_clause(table);
+
+                (format!("NOT ({})", clause), params)
+            }
+        }
+    }
+
+    fn cast_type(value: &PropValue) -> &'static str {
+        match value {
+            PropValue::String(_) | PropValue::Bytes(_) => "TEXT",
+            PropValue::Number(_) => "NUMBER",
+        }
+    }
+
+    /// Like [`to_sql_clause()`](QueryNode::to_sql_clause), but with parameters converted to
+    /// owned values so they can be bound to more than one statement.
+    pub(crate) fn to_sql_clause_values(
+        &self,
+        table: &str,
+    ) -> rusqlite::Result<(String, Vec<rusqlite::types::Value>)> {
+        let (clause, params) = self.to_sql_clause(table);
+
+        let values = params
+            .iter()
+            .map(|param| match param.to_sql()? {
+                ToSqlOutput::Borrowed(value) => Ok(value.into()),
+                ToSqlOutput::Owned(value) => Ok(value),
+                output => Err(rusqlite::Error::ToSqlConversionFailure(
+                    format!("unexpected query parameter {:?}", output).into(),
+                )),
+            })
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok((clause, values))
+    }
+
+    /// Like [`to_sql_clause()`](QueryNode::to_sql_clause), but with parameters written into the
+    /// SQL as literals, for statements that can't take parameters, like the `WHERE` clause of a
+    /// partial index.
+    pub(crate) fn to_sql_clause_literal(&self, table: &str) -> rusqlite::Result<String> {
+        use rusqlite::types::Value;
+
+        let (clause, values) = self.to_sql_clause_values(table)?;
+        let mut values = values.into_iter();
+
+        Ok(Self::rewrite_params(
+            &clause,
+            |_number, result| match values.next() {
+                Some(Value::Null) | None => result.push_str("NULL"),
+                Some(Value::Integer(n)) => result.push_str(&n.to_string()),
+                Some(Value::Real(f)) => result.push_str(&format!("{:?}", f)),
+                Some(Value::Text(s)) => result.push_str(&format!("'{}'", s.replace('\'', "''"))),
+                Some(Value::Blob(b)) => result.push_str(&format!(
+                    "X'{}'",
+                    b.iter()
+                        .map(|byte| format!("{:02x}", byte))
+                        .collect::<String>()
+                )),
+            },
+        ))
+    }
+
+    /// Like [`to_sql_clause_values()`](QueryNode::to_sql_clause_values), but with parameters
+    /// numbered (`?1`, `?2`, ...) so that identical values share a single bound parameter.
+    ///
+    /// This keeps queries that repeat a value many times, like an `And` of criteria on the same
+    /// value, under SQLite's limit on bound parameters. Any plain `?` parameters after the clause
+    /// are numbered after the returned values.
+    pub(crate) fn to_sql_clause_deduplicated(
+        &self,
+        table: &str,
+    ) -> rusqlite::Result<(String, Vec<rusqlite::types::Value>)> {
+        use rusqlite::types::Value;
+        use std::collections::HashMap;
+
+        #[derive(Hash, PartialEq, Eq)]
+        enum ValueKey<'v> {
+            Null,
+            Integer(i64),
+            Real(u64),
+            Text(&'v str),
+            Blob(&'v [u8]),
+        }
+
+        let (clause, values) = self.to_sql_clause_values(table)?;
+        let mut numbers = HashMap::new();
+        let mut unique_indices = Vec::new();
+        let mut values_iter = values.iter().enumerate();
+
+        let result = Self::rewrite_params(&clause, |_number, result| {
+            let (index, value) = values_iter
+                .next()
+                .expect("clause should have a value for each parameter");
+            let key = match value {
+                Value::Null => ValueKey::Null,
+                Value::Integer(n) => ValueKey::Integer(*n),
+                Value::Real(f) => ValueKey::Real(f.to_bits()),
+                Value::Text(s) => ValueKey::Text(s),
+                Value::Blob(b) => ValueKey::Blob(b),
+            };
+            let number = *numbers.entry(key).or_insert_with(|| {
+                unique_indices.push(index);
+                unique_indices.len()
+            });
+
+            result.push_str(&format!("?{}", number));
+        });
+
+        let values = unique_indices
+            .into_iter()
+            .map(|index| values[index].clone())
+            .collect();
+
+        Ok((result, values))
+    }
+
+    /// Rewrite each parameter in the given SQL with `write_param`, which is given the number of
+    /// the parameter, if any (as in `?2`), and the SQL written so far.
+    ///
+    /// Property names are embedded in double quotes or single-quoted JSON paths, so any `?` outside
+    /// of quotes is a parameter.
+    fn rewrite_params(
+        sql: &str,
+        mut write_param: impl FnMut(Option<usize>, &mut String),
+    ) -> String {
+        let mut result = String::with_capacity(sql.len());
+        let mut chars = sql.chars().peekable();
+        let mut quote = None;
+
+        while let Some(c) = chars.next() {
+            match (c, quote) {
+                ('"' | '\'', None) => {
+                    quote = Some(c);
+                    result.push(c);
+                }
+                (c, Some(q)) if c == q => {
+                    quote = None;
+                    result.push(c);
+                }
+                ('?', None) => {
+                    let mut number = String::new();
+                    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                        number.push(digit);
+                    }
+
+                    write_param(number.parse().ok(), &mut result);
+                }
+                (c, _) => result.push(c),
+            }
+        }
+
+        result
+    }
+
+    /// Count the plain `?` parameters in the given SQL, outside of quotes.
+    pub(crate) fn count_params(sql: &str) -> usize {
+        let mut count = 0;
+        Self::rewrite_params(sql, |number, _result| {
+            if number.is_none() {
+                count += 1;
+            }
+        });
+
+        count
+    }
+
+    /// Add `offset` to each numbered parameter in a clause from
+    /// [`to_sql_clause_deduplicated()`](QueryNode::to_sql_clause_deduplicated), so that it can
+    /// follow `offset` plain parameters in a statement.
+    pub(crate) fn offset_params(clause: &str, offset: usize) -> String {
+        Self::rewrite_params(clause, |number, result| {
+            let number = number.expect("parameters should be numbered");
+            result.push_str(&format!("?{}", number + offset));
+        })
+    }
+
+    /// The names of all properties this node matches against.
+    ///
+    /// The query of a [`PropInQuery`](QueryNode::PropInQuery) matches other objects, so neither
+    /// its properties nor its `field` are included.
+    pub(crate) fn property_names(&self) -> Vec<&str> {
+        match self {
+            QueryNode::Empty => Vec::new(),
+            QueryNode::PropEqual { name, .. }
+            | QueryNode::PropEqualCoerced { name, .. }
+            | QueryNode::PropLike { name, .. }
+            | QueryNode::PropPhrase { name, .. }
+            | QueryNode::PropMatches { name, .. }
+            | QueryNode::PropCompare { name, .. }
+            | QueryNode::PropBetween { name, .. }
+            | QueryNode::PropStartsWith { name, .. }
+            | QueryNode::PropIn { name, .. }
+            | QueryNode::PropExists { name }
+            | QueryNode::PropInQuery { name, .. } => vec![name.as_str()],
+            QueryNode::PropCompareProp { name, other, .. } => vec![name.as_str(), other.as_str()],
+            QueryNode::And(nodes) | QueryNode::Or(nodes) => {
+                nodes.iter().flat_map(QueryNode::property_names).collect()
+            }
+            QueryNode::Not(node) => node.property_names(),
         }
     }
 
-    fn equal_to_sql_clause(name: &String, value: &PropValue) -> (String, Vec<Box<dyn ToSql>>) {
-        if name == "object_id" {
+    /// Whether the given property name refers to the `object_id` column rather than a property.
+    pub(crate) fn is_object_id(name: &str) -> bool {
+        name == "object_id" || name == "object-id"
+    }
+
+    /// The SQL expression for the given property cast to the given type.
+    ///
+    /// This is shared with [`Store::create_index()`](crate::Store::create_index), as SQLite will
+    /// only use an index on an expression if the query uses exactly the same expression.
+    pub(crate) fn cast_property(name: &str, cast_type: &str) -> String {
+        format!(
+            "CAST(json_extract(properties, {}) AS {})",
+            Self::property_path(name),
+            cast_type
+        )
+    }
+
+    /// The SQL string literal for the JSON path of the given property.
+    ///
+    /// Properties are stored flat, so a name containing dots is quoted in the path to be read as
+    /// one key rather than a path through nested objects. Other names are left unquoted, so that
+    /// expressions still match indexes created by earlier versions.
+    pub(crate) fn property_path(name: &str) -> String {
+        if name.contains('.') {
+            format!("'$.\"{}\"'", name.replace('\'', "''"))
+        } else {
+            format!("\"$.{}\"", name)
+        }
+    }
+
+    /// The SQL expression for the given property as text, for matching against regular
+    /// expressions.
+    ///
+    /// The `object_id` is stored in its own column, so it is converted to text from there.
+    fn text_property(name: &str) -> String {
+        if Self::is_object_id(name) {
+            "CAST(object_id AS TEXT)".to_string()
+        } else {
+            Self::cast_property(name, "TEXT")
+        }
+    }
+
+    fn equal_to_sql_clause(name: &str, value: &PropValue) -> (String, Vec<Box<dyn ToSql>>) {
+        if Self::is_object_id(name) {
             return ("object_id = ?".to_string(), vec_params![value.clone()]);
         }
 
-        let cast_type = match value {
-            PropValue::String(_) => "TEXT",
-            PropValue::Number(_) => "NUMBER",
+        (
+            format!("{} = ?", Self::cast_property(name, Self::cast_type(value))),
+            vec_params![value.clone()],
+        )
+    }
+
+    fn equal_coerced_to_sql_clause(name: &str, value: &PropValue) -> (String, Vec<Box<dyn ToSql>>) {
+        let text = match value {
+            PropValue::Number(n) => n.to_string(),
+            PropValue::String(s) => s.clone(),
+            PropValue::Bytes(_) => value.to_json_string(),
         };
 
+        (
+            format!("{} = ?", Self::text_property(name)),
+            vec_params![text],
+        )
+    }
+
+    fn compare_to_sql_clause(
+        name: &str,
+        comparison: Comparison,
+        value: &PropValue,
+    ) -> (String, Vec<Box<dyn ToSql>>) {
+        if Self::is_object_id(name) {
+            return (
+                format!("object_id {} ?", comparison.to_sql_operator()),
+                vec_params![value.clone()],
+            );
+        }
+
         (
             format!(
-                "CAST(json_extract(properties, \"$.{}\") AS {}) = ?",
-                name, cast_type
-            )
-            .to_string(),
+                "{} {} ?",
+                Self::cast_property(name, Self::cast_type(value)),
+                comparison.to_sql_operator(),
+            ),
             vec_params![value.clone()],
         )
     }
 
-    fn like_to_sql_clause(name: &String, pattern: &String) -> (String, Vec<Box<dyn ToSql>>) {
+    fn between_to_sql_clause(
+        name: &str,
+        low: &PropValue,
+        high: &PropValue,
+    ) -> (String, Vec<Box<dyn ToSql>>) {
+        if Self::is_object_id(name) {
+            return (
+                "object_id BETWEEN ? AND ?".to_string(),
+                vec_params![low.clone(), high.clone()],
+            );
+        }
+
+        (
+            format!(
+                "{} BETWEEN ? AND ?",
+                Self::cast_property(name, Self::cast_type(low)),
+            ),
+            vec_params![low.clone(), high.clone()],
+        )
+    }
+
+    fn compare_prop_to_sql_clause(
+        name: &str,
+        comparison: Comparison,
+        other: &str,
+    ) -> (String, Vec<Box<dyn ToSql>>) {
+        let number = |name: &str| {
+            if Self::is_object_id(name) {
+                "object_id".to_string()
+            } else {
+                Self::cast_property(name, "NUMBER")
+            }
+        };
+
+        (
+            format!(
+                "{} {} {}",
+                number(name),
+                comparison.to_sql_operator(),
+                number(other),
+            ),
+            vec_params![],
+        )
+    }
+
+    fn starts_with_to_sql_clause(name: &str, prefix: &str) -> (String, Vec<Box<dyn ToSql>>) {
+        (
+            format!("{} REGEXP ?", Self::text_property(name)),
+            vec_params![format!(r"^{}", regex::escape(prefix))],
+        )
+    }
+
+    fn in_to_sql_clause(name: &str, values: &[PropValue]) -> (String, Vec<Box<dyn ToSql>>) {
+        let (numbers, strings): (Vec<_>, Vec<_>) = values
+            .iter()
+            .cloned()
+            .partition(|value| matches!(value, PropValue::Number(_)));
+
+        let mut clauses = Vec::new();
+        let mut params = vec_params![];
+
+        for (group, cast_type) in [(numbers, "NUMBER"), (strings, "TEXT")] {
+            if group.is_empty() {
+                continue;
+            }
+
+            let column = if Self::is_object_id(name) {
+                "object_id".to_string()
+            } else {
+                Self::cast_property(name, cast_type)
+            };
+
+            clauses.push(format!("{} IN (SELECT value FROM json_each(?))", column));
+            params.push(Box::new(serde_json::to_string(&group).unwrap()) as Box<dyn ToSql>);
+        }
+
+        match clauses.len() {
+            0 => ("1=0".to_string(), params),
+            1 => (clauses.remove(0), params),
+            _ => (format!("({})", clauses.join(" OR ")), params),
+        }
+    }
+
+    /// Match each of the words in `pattern` in order, with `gap` between them.
+    fn words_to_sql_clause(name: &str, pattern: &str, gap: &str) -> (String, Vec<Box<dyn ToSql>>) {
         let words = pattern.split(" ").filter(|word| word != &"");
         let wrapped_words: Vec<String> = words
             .map(|word| {
@@ -93,21 +603,85 @@ impl QueryNode {
                 format!(r"\b{}\b", quoted_pieces.join(r"\w*"))
             })
             .collect();
-        let wrapped_words_phrase = wrapped_words.join(r".*?");
+        let wrapped_words_phrase = wrapped_words.join(gap);
 
         (
-            format!(
-                "CAST(json_extract(properties, \"$.{}\") AS TEXT) REGEXP ?",
-                name
-            )
-            .to_string(),
+            format!("{} REGEXP ?", Self::text_property(name)),
             vec_params![format!(r"(?i){}", wrapped_words_phrase).to_string()],
         )
     }
 
-    fn and_to_sql_clause(nodes: &Vec<QueryNode>) -> (String, Vec<Box<dyn ToSql>>) {
+    fn matches_to_sql_clause(name: &str, regex: &str) -> (String, Vec<Box<dyn ToSql>>) {
+        (
+            format!("{} REGEXP ?", Self::text_property(name)),
+            vec_params![regex.to_string()],
+        )
+    }
+
+    fn exists_to_sql_clause(name: &str) -> (String, Vec<Box<dyn ToSql>>) {
+        if Self::is_object_id(name) {
+            return ("1=1".to_string(), vec_params![]);
+        }
+
+        (
+            format!(
+                "json_type(properties, {}) IS NOT NULL",
+                Self::property_path(name)
+            ),
+            vec_params![],
+        )
+    }
+
+    fn in_query_to_sql_clause(
+        table: &str,
+        name: &str,
+        query: &QueryNode,
+        field: &str,
+    ) -> (String, Vec<Box<dyn ToSql>>) {
+        let value = |name: &str| {
+            if Self::is_object_id(name) {
+                "object_id".to_string()
+            } else {
+                format!("json_extract(properties, {})", Self::property_path(name))
+            }
+        };
+
+        // The subquery's unqualified columns refer to its own table.
+        let (clause, params) = query.to_sql_clause(table);
+
+        (
+            format!(
+                "{} IN (SELECT {} FROM {} WHERE {})",
+                value(name),
+                value(field),
+                table,
+                clause
+            ),
+            params,
+        )
+    }
+
+    fn or_to_sql_clause(table: &str, nodes: &[QueryNode]) -> (String, Vec<Box<dyn ToSql>>) {
+        if nodes.is_empty() {
+            return ("1=0".to_string(), vec_params![]);
+        }
+
         let (clauses, param_vecs): (Vec<_>, Vec<_>) =
-            nodes.iter().map(|node| node.to_sql_clause()).unzip();
+            nodes.iter().map(|node| node.to_sql_clause(table)).unzip();
+
+        (
+            format!("({})", clauses.join(" OR ")),
+            param_vecs.into_iter().flatten().collect(),
+        )
+    }
+
+    fn and_to_sql_clause(table: &str, nodes: &[QueryNode]) -> (String, Vec<Box<dyn ToSql>>) {
+        if nodes.is_empty() {
+            return ("1=1".to_string(), vec_params![]);
+        }
+
+        let (clauses, param_vecs): (Vec<_>, Vec<_>) =
+            nodes.iter().map(|node| node.to_sql_clause(table)).unzip();
 
         (
             clauses.join(" AND "),
@@ -116,11 +690,35 @@ impl QueryNode {
     }
 }
 
+/// Converts a property name and value into a [`PropEqual`](QueryNode::PropEqual).
+impl From<(String, PropValue)> for QueryNode {
+    fn from((name, value): (String, PropValue)) -> Self {
+        QueryNode::PropEqual { name, value }
+    }
+}
+
+/// Combines several nodes into an [`And`](QueryNode::And).
+///
+/// As with [`QueryBuilder`](crate::query_builder::QueryBuilder), no nodes will produce
+/// [`Empty`](QueryNode::Empty) and a single node will be returned as is.
+impl std::iter::FromIterator<QueryNode> for QueryNode {
+    fn from_iter<I: IntoIterator<Item = QueryNode>>(iter: I) -> Self {
+        let mut nodes: Vec<_> = iter.into_iter().collect();
+
+        match nodes.len() {
+            0 => QueryNode::Empty,
+            1 => nodes.remove(0),
+            _ => QueryNode::And(nodes),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::QueryNode::*;
     use super::*;
-    use rusqlite::types::{ToSqlOutput, ValueRef};
+    use crate::Q;
+    use rusqlite::types::ValueRef;
 
     macro_rules! query_test {
         ( $description:expr, $query:expr, $where_clause:expr, [$($params:expr),* $(,)?] $(,)?) => {
@@ -175,6 +773,15 @@ mod tests {
                 "object_id = ?",
                 [42],
             ),
+            query_test!(
+                "object-id equal",
+                PropEqual {
+                    name: "object-id".to_string(),
+                    value: 42.into(),
+                },
+                "object_id = ?",
+                [42],
+            ),
             query_test!(
                 "simple word like",
                 PropLike {
@@ -220,6 +827,195 @@ mod tests {
                 "CAST(json_extract(properties, \"$.name\") AS TEXT) REGEXP ?",
                 [r"(?i)\blon\w*\b.*?\b\w*hrase\b"],
             ),
+            query_test!(
+                "object_id like",
+                PropLike {
+                    name: "object_id".to_string(),
+                    pattern: "1*".to_string(),
+                },
+                "CAST(object_id AS TEXT) REGEXP ?",
+                [r"(?i)\b1\w*\b"],
+            ),
+            query_test!(
+                "object-id starts with",
+                PropStartsWith {
+                    name: "object-id".to_string(),
+                    prefix: "1".to_string(),
+                },
+                "CAST(object_id AS TEXT) REGEXP ?",
+                [r"^1"],
+            ),
+            query_test!(
+                "multi-word phrase",
+                PropPhrase {
+                    name: "name".to_string(),
+                    phrase: "lon* *hrase".to_string(),
+                },
+                "CAST(json_extract(properties, \"$.name\") AS TEXT) REGEXP ?",
+                [r"(?i)\blon\w*\b\s+\b\w*hrase\b"],
+            ),
+            query_test!(
+                "regex matches",
+                PropMatches {
+                    name: "name".to_string(),
+                    regex: r"^a+\d$".to_string(),
+                },
+                "CAST(json_extract(properties, \"$.name\") AS TEXT) REGEXP ?",
+                [r"^a+\d$"],
+            ),
+            query_test!(
+                "number greater than",
+                PropCompare {
+                    name: "name".to_string(),
+                    comparison: Comparison::Greater,
+                    value: 42.into(),
+                },
+                "CAST(json_extract(properties, \"$.name\") AS NUMBER) > ?",
+                [42],
+            ),
+            query_test!(
+                "string less than or equal",
+                PropCompare {
+                    name: "name".to_string(),
+                    comparison: Comparison::LessOrEqual,
+                    value: "value".into(),
+                },
+                "CAST(json_extract(properties, \"$.name\") AS TEXT) <= ?",
+                ["value"],
+            ),
+            query_test!(
+                "number between",
+                PropBetween {
+                    name: "name".to_string(),
+                    low: 4.into(),
+                    high: 8.into(),
+                },
+                "CAST(json_extract(properties, \"$.name\") AS NUMBER) BETWEEN ? AND ?",
+                [4, 8],
+            ),
+            query_test!(
+                "object_id greater than",
+                PropCompare {
+                    name: "object_id".to_string(),
+                    comparison: Comparison::Greater,
+                    value: 100.into(),
+                },
+                "object_id > ?",
+                [100],
+            ),
+            query_test!(
+                "object-id less than",
+                PropCompare {
+                    name: "object-id".to_string(),
+                    comparison: Comparison::Less,
+                    value: 200.into(),
+                },
+                "object_id < ?",
+                [200],
+            ),
+            query_test!(
+                "object_id between",
+                PropBetween {
+                    name: "object_id".to_string(),
+                    low: 100.into(),
+                    high: 200.into(),
+                },
+                "object_id BETWEEN ? AND ?",
+                [100, 200],
+            ),
+            query_test!(
+                "dotted name greater than",
+                PropCompare {
+                    name: "photo.width".to_string(),
+                    comparison: Comparison::Greater,
+                    value: 4.into(),
+                },
+                "CAST(json_extract(properties, '$.\"photo.width\"') AS NUMBER) > ?",
+                [4],
+            ),
+            query_test!(
+                "property greater than property",
+                PropCompareProp {
+                    name: "width".to_string(),
+                    comparison: Comparison::Greater,
+                    other: "height".to_string(),
+                },
+                "CAST(json_extract(properties, \"$.width\") AS NUMBER) > CAST(json_extract(properties, \"$.height\") AS NUMBER)",
+                [],
+            ),
+            query_test!(
+                "object_id at most property",
+                PropCompareProp {
+                    name: "object_id".to_string(),
+                    comparison: Comparison::LessOrEqual,
+                    other: "parent_id".to_string(),
+                },
+                "object_id <= CAST(json_extract(properties, \"$.parent_id\") AS NUMBER)",
+                [],
+            ),
+            query_test!(
+                "starts with",
+                PropStartsWith {
+                    name: "name".to_string(),
+                    prefix: "a.b".to_string(),
+                },
+                "CAST(json_extract(properties, \"$.name\") AS TEXT) REGEXP ?",
+                [r"^a\.b"],
+            ),
+            query_test!(
+                "coerced equal",
+                PropEqualCoerced {
+                    name: "name".to_string(),
+                    value: 2021.into(),
+                },
+                "CAST(json_extract(properties, \"$.name\") AS TEXT) = ?",
+                ["2021"],
+            ),
+            query_test!(
+                "coerced equal on object_id",
+                PropEqualCoerced {
+                    name: "object_id".to_string(),
+                    value: "3".into(),
+                },
+                "CAST(object_id AS TEXT) = ?",
+                ["3"],
+            ),
+            query_test!(
+                "number in",
+                PropIn {
+                    name: "name".to_string(),
+                    values: vec![1.into(), 2.into()],
+                },
+                "CAST(json_extract(properties, \"$.name\") AS NUMBER) IN (SELECT value FROM json_each(?))",
+                ["[1,2]"],
+            ),
+            query_test!(
+                "mixed in",
+                PropIn {
+                    name: "name".to_string(),
+                    values: vec![1.into(), "two".into()],
+                },
+                "(CAST(json_extract(properties, \"$.name\") AS NUMBER) IN (SELECT value FROM json_each(?)) OR CAST(json_extract(properties, \"$.name\") AS TEXT) IN (SELECT value FROM json_each(?)))",
+                ["[1]", "[\"two\"]"],
+            ),
+            query_test!(
+                "object_id in",
+                PropIn {
+                    name: "object_id".to_string(),
+                    values: vec![1.into(), 2.into()],
+                },
+                "object_id IN (SELECT value FROM json_each(?))",
+                ["[1,2]"],
+            ),
+            query_test!(
+                "empty in",
+                PropIn {
+                    name: "name".to_string(),
+                    values: vec![],
+                },
+                "1=0",
+                [],
+            ),
             query_test!(
                 "anded queries",
                 And(vec![
@@ -239,10 +1035,183 @@ mod tests {
                 "CAST(json_extract(properties, \"$.name1\") AS TEXT) = ? AND CAST(json_extract(properties, \"$.name2\") AS TEXT) = ? AND CAST(json_extract(properties, \"$.name3\") AS TEXT) = ?",
                 ["value1", "value2", "value3"],
             ),
+            query_test!(
+                "exists",
+                PropExists {
+                    name: "name".to_string(),
+                },
+                "json_type(properties, \"$.name\") IS NOT NULL",
+                [],
+            ),
+            query_test!(
+                "not like",
+                Q.not_like("name", "phrase").build(),
+                "NOT (CAST(json_extract(properties, \"$.name\") AS TEXT) REGEXP ?)",
+                [r"(?i)\bphrase\b"],
+            ),
+            query_test!(
+                "not any of",
+                Q.not_any_of("name", [1, 2]).build(),
+                "NOT (CAST(json_extract(properties, \"$.name\") AS NUMBER) IN (SELECT value FROM json_each(?)))",
+                ["[1,2]"],
+            ),
+            query_test!(
+                "optional equal with a value",
+                Q.equal_opt("name", Some("value")).build(),
+                "CAST(json_extract(properties, \"$.name\") AS TEXT) = ?",
+                ["value"],
+            ),
+            query_test!(
+                "optional equal without a value",
+                Q.equal_opt::<&str>("name", None).build(),
+                "NOT (json_type(properties, \"$.name\") IS NOT NULL)",
+                [],
+            ),
+            query_test!(
+                "missing all",
+                And(vec![
+                    Not(Box::new(PropExists {
+                        name: "width".to_string(),
+                    })),
+                    Not(Box::new(PropExists {
+                        name: "height".to_string(),
+                    })),
+                ]),
+                "NOT (json_type(properties, \"$.width\") IS NOT NULL) AND NOT (json_type(properties, \"$.height\") IS NOT NULL)",
+                [],
+            ),
+            query_test!(
+                "missing any",
+                Or(vec![
+                    Not(Box::new(PropExists {
+                        name: "width".to_string(),
+                    })),
+                    Not(Box::new(PropExists {
+                        name: "height".to_string(),
+                    })),
+                ]),
+                "(NOT (json_type(properties, \"$.width\") IS NOT NULL) OR NOT (json_type(properties, \"$.height\") IS NOT NULL))",
+                [],
+            ),
+            query_test!(
+                "ored queries",
+                Or(vec![
+                    PropEqual {
+                        name: "name1".to_string(),
+                        value: "value1".into(),
+                    },
+                    And(vec![
+                        PropEqual {
+                            name: "name2".to_string(),
+                            value: "value2".into(),
+                        },
+                        PropEqual {
+                            name: "name3".to_string(),
+                            value: 3.into(),
+                        },
+                    ]),
+                ]),
+                "(CAST(json_extract(properties, \"$.name1\") AS TEXT) = ? OR CAST(json_extract(properties, \"$.name2\") AS TEXT) = ? AND CAST(json_extract(properties, \"$.name3\") AS NUMBER) = ?)",
+                ["value1", "value2", 3],
+            ),
+            query_test!(
+                "like any with other criteria",
+                Q.like_any(&["title", "notes"], "lucky")
+                    .equal("kind", "note")
+                    .build(),
+                "(CAST(json_extract(properties, \"$.title\") AS TEXT) REGEXP ? OR CAST(json_extract(properties, \"$.notes\") AS TEXT) REGEXP ?) AND CAST(json_extract(properties, \"$.kind\") AS TEXT) = ?",
+                [r"(?i)\blucky\b", r"(?i)\blucky\b", "note"],
+            ),
+            query_test!(
+                "like any without fields",
+                Q.like_any(&[], "lucky").build(),
+                "1=0",
+                [],
+            ),
+            query_test!("empty or", Or(vec![]), "1=0", []),
+            query_test!("empty and", And(vec![]), "1=1", []),
+            query_test!(
+                "or with empty and",
+                Or(vec![
+                    And(vec![]),
+                    PropEqual {
+                        name: "name".to_string(),
+                        value: "value".into(),
+                    },
+                ]),
+                "(1=1 OR CAST(json_extract(properties, \"$.name\") AS TEXT) = ?)",
+                ["value"],
+            ),
+            query_test!(
+                "in query",
+                PropInQuery {
+                    name: "group_id".to_string(),
+                    query: Box::new(And(vec![
+                        PropEqual {
+                            name: "kind".to_string(),
+                            value: "group".into(),
+                        },
+                        PropEqual {
+                            name: "size".to_string(),
+                            value: 2.into(),
+                        },
+                    ])),
+                    field: "object_id".to_string(),
+                },
+                "json_extract(properties, \"$.group_id\") IN (SELECT object_id FROM objects WHERE CAST(json_extract(properties, \"$.kind\") AS TEXT) = ? AND CAST(json_extract(properties, \"$.size\") AS NUMBER) = ?)",
+                ["group", 2],
+            ),
+            query_test!(
+                "object_id in query of property",
+                PropInQuery {
+                    name: "object_id".to_string(),
+                    query: Box::new(Empty),
+                    field: "parent".to_string(),
+                },
+                "object_id IN (SELECT json_extract(properties, \"$.parent\") FROM objects WHERE 1=1)",
+                [],
+            ),
+            query_test!(
+                "grouped and",
+                Q.equal("kind", "shape")
+                    .and(|b| b.equal("width", 1).equal("height", 2))
+                    .build(),
+                "CAST(json_extract(properties, \"$.kind\") AS TEXT) = ? AND CAST(json_extract(properties, \"$.width\") AS NUMBER) = ? AND CAST(json_extract(properties, \"$.height\") AS NUMBER) = ?",
+                ["shape", 1, 2],
+            ),
+            query_test!(
+                "grouped and in or",
+                Or(vec![
+                    Q.and(|b| b.equal("width", 1).equal("height", 2)).build(),
+                    Q.exists("size").build(),
+                ]),
+                "(CAST(json_extract(properties, \"$.width\") AS NUMBER) = ? AND CAST(json_extract(properties, \"$.height\") AS NUMBER) = ? OR json_type(properties, \"$.size\") IS NOT NULL)",
+                [1, 2],
+            ),
         ];
 
         for (description, query, expected_where_clause, expected_params) in &tests {
-            let (actual_where_clause, actual_params) = &query.to_sql_clause();
+            let (actual_where_clause, actual_params) = &query.to_sql_clause("objects");
+
+            let (value_where_clause, values) = query.to_sql_clause_values("objects").unwrap();
+            assert_eq!(
+                &value_where_clause, actual_where_clause,
+                "{} value where clause",
+                description
+            );
+            assert_eq!(
+                values,
+                actual_params
+                    .iter()
+                    .map(|param| match param.to_sql().unwrap() {
+                        ToSqlOutput::Borrowed(value) => value.into(),
+                        ToSqlOutput::Owned(value) => value,
+                        output => panic!("unexpected output {:?}", output),
+                    })
+                    .collect::<Vec<rusqlite::types::Value>>(),
+                "{} values",
+                description
+            );
 
             assert_eq!(
                 expected_where_clause, actual_where_clause,
@@ -257,4 +1226,162 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn nodes_can_be_converted_from_pairs_and_iterators() {
+        assert_eq!(
+            QueryNode::from(("name".to_string(), PropValue::from("value"))),
+            PropEqual {
+                name: "name".to_string(),
+                value: "value".into(),
+            }
+        );
+
+        let properties = [("name1", "value1"), ("name2", "value2")];
+        assert_eq!(
+            properties
+                .iter()
+                .map(|(name, value)| (name.to_string(), PropValue::from(*value)).into())
+                .collect::<QueryNode>(),
+            And(vec![
+                PropEqual {
+                    name: "name1".to_string(),
+                    value: "value1".into(),
+                },
+                PropEqual {
+                    name: "name2".to_string(),
+                    value: "value2".into(),
+                },
+            ])
+        );
+
+        assert_eq!(
+            std::iter::once(PropExists {
+                name: "name".to_string()
+            })
+            .collect::<QueryNode>(),
+            PropExists {
+                name: "name".to_string()
+            }
+        );
+        assert_eq!(std::iter::empty().collect::<QueryNode>(), Empty);
+    }
+
+    #[test]
+    fn parameters_can_be_written_as_literals() {
+        let query = Q
+            .equal("what?", "it's")
+            .greater_than("width", 5)
+            .equal("data", vec![0u8, 255])
+            .build();
+
+        assert_eq!(
+            query.to_sql_clause_literal("objects").unwrap(),
+            "CAST(json_extract(properties, \"$.what?\") AS TEXT) = 'it''s' AND CAST(json_extract(properties, \"$.width\") AS NUMBER) > 5 AND CAST(json_extract(properties, \"$.data\") AS TEXT) = '{\"base64\":\"AP8=\"}'"
+        );
+
+        // Dotted names are written in single-quoted paths, which may hold an odd number of
+        // double quotes.
+        assert_eq!(
+            Q.equal("it's \".what?", 5)
+                .build()
+                .to_sql_clause_literal("objects")
+                .unwrap(),
+            "CAST(json_extract(properties, '$.\"it''s \".what?\"') AS NUMBER) = 5"
+        );
+    }
+
+    #[test]
+    fn identical_parameters_are_deduplicated() {
+        let query = Q
+            .equal("what?", "one")
+            .greater_than("width", 5)
+            .equal("name", "one")
+            .at_most("height", 5)
+            .build();
+
+        let (clause, values) = query.to_sql_clause_deduplicated("objects").unwrap();
+        assert_eq!(
+            clause,
+            "CAST(json_extract(properties, \"$.what?\") AS TEXT) = ?1 AND CAST(json_extract(properties, \"$.width\") AS NUMBER) > ?2 AND CAST(json_extract(properties, \"$.name\") AS TEXT) = ?1 AND CAST(json_extract(properties, \"$.height\") AS NUMBER) <= ?2"
+        );
+        assert_eq!(values, vec!["one".to_string().into(), 5i64.into()]);
+
+        assert_eq!(
+            QueryNode::count_params("SELECT json_extract(properties, ?) AS \"?\" FROM objects"),
+            1
+        );
+        assert_eq!(
+            QueryNode::offset_params(&clause, 2),
+            clause.replace("?1", "?3").replace("?2", "?4")
+        );
+    }
+
+    #[test]
+    fn phrases_only_match_adjacent_words() {
+        let value = "why the lucky stiff";
+
+        for (pattern, like_matches, phrase_matches) in [
+            ("lucky stiff", true, true),
+            ("the luck*", true, true),
+            ("why lucky", true, false),
+            ("stiff lucky", false, false),
+        ] {
+            for (query, expected) in [
+                (
+                    PropLike {
+                        name: "name".to_string(),
+                        pattern: pattern.to_string(),
+                    },
+                    like_matches,
+                ),
+                (
+                    PropPhrase {
+                        name: "name".to_string(),
+                        phrase: pattern.to_string(),
+                    },
+                    phrase_matches,
+                ),
+            ] {
+                let (_, params) = query.to_sql_clause("objects");
+                let regex = regex::Regex::new(&stringify_params(&params)[0]).unwrap();
+
+                assert_eq!(regex.is_match(value), expected, "{:?}", query);
+            }
+        }
+    }
+
+    #[test]
+    fn like_and_starts_with_match_numbers_as_text() {
+        for (value, pattern, like_matches, starts_with_matches) in [
+            ("2021", "20", false, true),
+            ("2021", "20*", true, false),
+            ("2021", "2021", true, true),
+            ("1999", "20*", false, false),
+            ("20.5", "20", true, true),
+        ] {
+            for (query, expected) in [
+                (
+                    PropLike {
+                        name: "year".to_string(),
+                        pattern: pattern.to_string(),
+                    },
+                    like_matches,
+                ),
+                (
+                    PropStartsWith {
+                        name: "year".to_string(),
+                        prefix: pattern.to_string(),
+                    },
+                    starts_with_matches,
+                ),
+            ] {
+                let (clause, params) = query.to_sql_clause("objects");
+                assert!(clause.starts_with("CAST(json_extract(properties, \"$.year\") AS TEXT)"));
+
+                let regex = regex::Regex::new(&stringify_params(&params)[0]).unwrap();
+                assert_eq!(regex.is_match(value), expected, "{:?} on {}", query, value);
+            }
+        }
+    }
 }
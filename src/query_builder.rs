@@ -1,6 +1,6 @@
-use crate::object::PropValue;
-use crate::query::QueryNode;
+use crate::object::{ObjectShape, PropValue};
 use crate::query::QueryNode::*;
+use crate::query::{Comparison, QueryError, QueryNode};
 
 /// A convenience class for creating [`QueryNode`] objects. This enum should be used by calling
 /// methods on [`Q`], rather than by creating a new [`QueryBuilder`] yourself.
@@ -39,6 +39,11 @@ impl QueryBuilder {
         })
     }
 
+    /// Add the criteria that the object have any of the given IDs.
+    pub fn ids(self, values: impl IntoIterator<Item = i64>) -> Self {
+        self.any_of("object_id", values)
+    }
+
     /// Add the criteria that the given field has exactly the given value.
     pub fn equal(self, name: impl Into<String>, value: impl Into<PropValue>) -> Self {
         self.add(PropEqual {
@@ -47,6 +52,55 @@ impl QueryBuilder {
         })
     }
 
+    /// Add the criteria that the given field has the same text form as the given value, whether
+    /// it is stored as a number or a string.
+    ///
+    /// See [`PropEqualCoerced`] for details and how this interacts with indexes.
+    pub fn equal_coerced(self, name: impl Into<String>, value: impl Into<PropValue>) -> Self {
+        self.add(PropEqualCoerced {
+            name: name.into(),
+            value: value.into(),
+        })
+    }
+
+    /// Add the criteria that the given field has exactly the given value, or, if `value` is
+    /// `None`, that the object not have the given field at all.
+    ///
+    /// Objects cannot store a null property, so `None` is the same as
+    /// [`.missing()`](QueryBuilder::missing); it does not look for a stored JSON `null`.
+    pub fn equal_opt<V: Into<PropValue>>(self, name: impl Into<String>, value: Option<V>) -> Self {
+        match value {
+            Some(value) => self.equal(name, value),
+            None => self.missing(name),
+        }
+    }
+
+    /// Add the criteria that the given field has exactly one of the given values.
+    pub fn any_of<V: Into<PropValue>>(
+        self,
+        name: impl Into<String>,
+        values: impl IntoIterator<Item = V>,
+    ) -> Self {
+        self.add(PropIn {
+            name: name.into(),
+            values: values.into_iter().map(|value| value.into()).collect(),
+        })
+    }
+
+    /// Add the criteria that the given field has none of the given values.
+    ///
+    /// Objects without the field are not matched.
+    pub fn not_any_of<V: Into<PropValue>>(
+        self,
+        name: impl Into<String>,
+        values: impl IntoIterator<Item = V>,
+    ) -> Self {
+        self.add(Not(Box::new(PropIn {
+            name: name.into(),
+            values: values.into_iter().map(|value| value.into()).collect(),
+        })))
+    }
+
     /// Add the criteria that the given field have contents matching the given value.
     ///
     /// See [`PropLike`] for the supported syntax.
@@ -57,6 +111,182 @@ impl QueryBuilder {
         })
     }
 
+    /// Add the criteria that any of the given fields have contents matching the given value.
+    ///
+    /// The fields are checked as one [`Or`] group, so the query still requires all other criteria.
+    /// If no fields are given, nothing is matched. See [`PropLike`] for the supported syntax.
+    pub fn like_any(self, names: &[&str], pattern: impl Into<String>) -> Self {
+        let pattern = pattern.into();
+
+        self.add(Or(names
+            .iter()
+            .map(|name| PropLike {
+                name: name.to_string(),
+                pattern: pattern.clone(),
+            })
+            .collect()))
+    }
+
+    /// Add the criteria that the given field not have contents matching the given value.
+    ///
+    /// Objects without the field are not matched. See [`PropLike`] for the supported syntax.
+    pub fn not_like(self, name: impl Into<String>, pattern: impl Into<String>) -> Self {
+        self.add(Not(Box::new(PropLike {
+            name: name.into(),
+            pattern: pattern.into(),
+        })))
+    }
+
+    /// Add the criteria that the given field contain the given words next to each other.
+    ///
+    /// See [`PropPhrase`] for the supported syntax.
+    pub fn phrase(self, name: impl Into<String>, phrase: impl Into<String>) -> Self {
+        self.add(PropPhrase {
+            name: name.into(),
+            phrase: phrase.into(),
+        })
+    }
+
+    /// Add the criteria that the given field match the given regular expression.
+    ///
+    /// The regular expression is compiled immediately, so that an invalid pattern is reported here
+    /// rather than when the query is run. See [`PropMatches`] for details.
+    pub fn matches(self, name: impl Into<String>, regex: &str) -> Result<Self, regex::Error> {
+        regex::Regex::new(regex)?;
+
+        Ok(self.add(PropMatches {
+            name: name.into(),
+            regex: regex.to_string(),
+        }))
+    }
+
+    fn compare(self, name: impl Into<String>, comparison: Comparison, value: PropValue) -> Self {
+        self.add(PropCompare {
+            name: name.into(),
+            comparison,
+            value,
+        })
+    }
+
+    /// Add the criteria that the given field be greater than the given value.
+    pub fn greater_than(self, name: impl Into<String>, value: impl Into<PropValue>) -> Self {
+        self.compare(name, Comparison::Greater, value.into())
+    }
+
+    /// Add the criteria that the given field be greater than or equal to the given value.
+    pub fn at_least(self, name: impl Into<String>, value: impl Into<PropValue>) -> Self {
+        self.compare(name, Comparison::GreaterOrEqual, value.into())
+    }
+
+    /// Add the criteria that the given field be less than the given value.
+    pub fn less_than(self, name: impl Into<String>, value: impl Into<PropValue>) -> Self {
+        self.compare(name, Comparison::Less, value.into())
+    }
+
+    /// Add the criteria that the given field be less than or equal to the given value.
+    pub fn at_most(self, name: impl Into<String>, value: impl Into<PropValue>) -> Self {
+        self.compare(name, Comparison::LessOrEqual, value.into())
+    }
+
+    /// Add the criteria that the given field be between `low` and `high`, inclusive.
+    pub fn between(
+        self,
+        name: impl Into<String>,
+        low: impl Into<PropValue>,
+        high: impl Into<PropValue>,
+    ) -> Self {
+        self.add(PropBetween {
+            name: name.into(),
+            low: low.into(),
+            high: high.into(),
+        })
+    }
+
+    /// Add the criteria that the given field compare to the `other` field as specified.
+    ///
+    /// See [`PropCompareProp`] for details.
+    pub fn compare_fields(
+        self,
+        name: impl Into<String>,
+        comparison: Comparison,
+        other: impl Into<String>,
+    ) -> Self {
+        self.add(PropCompareProp {
+            name: name.into(),
+            comparison,
+            other: other.into(),
+        })
+    }
+
+    /// Add the criteria that the given field start with the given prefix.
+    ///
+    /// See [`PropStartsWith`] for details.
+    pub fn starts_with(self, name: impl Into<String>, prefix: impl Into<String>) -> Self {
+        self.add(PropStartsWith {
+            name: name.into(),
+            prefix: prefix.into(),
+        })
+    }
+
+    /// Add the criteria that the given field be equal to the `select_field` field of any object
+    /// matching `subquery`.
+    ///
+    /// See [`PropInQuery`] for details.
+    pub fn in_subquery(
+        self,
+        name: impl Into<String>,
+        subquery: impl Into<QueryNode>,
+        select_field: impl Into<String>,
+    ) -> Self {
+        self.add(PropInQuery {
+            name: name.into(),
+            query: Box::new(subquery.into()),
+            field: select_field.into(),
+        })
+    }
+
+    /// Add the criteria that the object have the given field, whatever its value.
+    pub fn exists(self, name: impl Into<String>) -> Self {
+        self.add(PropExists { name: name.into() })
+    }
+
+    /// Add the criteria that the object not have the given field.
+    pub fn missing(self, name: impl Into<String>) -> Self {
+        self.add(Self::missing_node(name))
+    }
+
+    /// Add the criteria that the object have none of the given fields.
+    pub fn missing_all(self, names: &[&str]) -> Self {
+        names
+            .iter()
+            .fold(self, |builder, name| builder.missing(*name))
+    }
+
+    /// Add the criteria that the object lack at least one of the given fields.
+    pub fn missing_any(self, names: &[&str]) -> Self {
+        self.add(Or(names
+            .iter()
+            .map(|name| Self::missing_node(*name))
+            .collect()))
+    }
+
+    /// Add the criteria built by `f` as a single, explicitly grouped node.
+    ///
+    /// `f` is called with an empty [`QueryBuilder`], and all criteria it adds end up in one
+    /// [`And`] node, which keeps them together when the result is combined with other nodes, such
+    /// as in an [`Or`]. If `f` adds no criteria, nothing is added.
+    pub fn and(self, f: impl FnOnce(QueryBuilder) -> QueryBuilder) -> Self {
+        match f(Q) {
+            QueryBuilder::Empty => self,
+            QueryBuilder::Single(node) => self.add(And(vec![node])),
+            QueryBuilder::And(nodes) => self.add(And(nodes)),
+        }
+    }
+
+    fn missing_node(name: impl Into<String>) -> QueryNode {
+        Not(Box::new(PropExists { name: name.into() }))
+    }
+
     /// Consume this [`QueryBuilder`] and build a [`QueryNode`].
     pub fn build(self) -> QueryNode {
         match self {
@@ -65,6 +295,35 @@ impl QueryBuilder {
             QueryBuilder::Empty => Empty,
         }
     }
+
+    /// Like [`build()`](QueryBuilder::build), but checking that every property the query matches
+    /// against belongs to the shape `T`.
+    ///
+    /// The properties of `T` are those in [`PROPERTIES`](ObjectShape::PROPERTIES), those matched by
+    /// [`T::q()`](crate::Queryable::q), such as fixed fields, and `object_id`. Aliases and
+    /// `#[rest_fields]` are not known, so properties only read through them are rejected. Returns
+    /// [`QueryError::UnknownProperty`] for the first property that isn't known.
+    pub fn build_for<T: ObjectShape>(self) -> Result<QueryNode, QueryError> {
+        let query = self.build();
+        let shape_query = T::q().build();
+
+        let known = |name: &str| {
+            QueryNode::is_object_id(name)
+                || T::PROPERTIES
+                    .iter()
+                    .any(|(property, _kind)| *property == name)
+                || shape_query.property_names().contains(&name)
+        };
+
+        if let Some(name) = query.property_names().into_iter().find(|name| !known(name)) {
+            return Err(QueryError::UnknownProperty {
+                name: name.to_string(),
+                shape: std::any::type_name::<T>().to_string(),
+            });
+        }
+
+        Ok(query)
+    }
 }
 
 impl Into<QueryNode> for QueryBuilder {
@@ -103,6 +362,29 @@ mod tests {
                     value: 42.into(),
                 },
             ),
+            builder_test!(
+                "coerced equal",
+                Q.equal_coerced("year", 2021).build(),
+                PropEqualCoerced {
+                    name: "year".to_string(),
+                    value: 2021.into(),
+                },
+            ),
+            builder_test!(
+                "optional equal with a value",
+                Q.equal_opt("name", Some(42)).build(),
+                PropEqual {
+                    name: "name".to_string(),
+                    value: 42.into(),
+                },
+            ),
+            builder_test!(
+                "optional equal without a value",
+                Q.equal_opt::<i64>("name", None).build(),
+                Not(Box::new(PropExists {
+                    name: "name".to_string(),
+                })),
+            ),
             builder_test!(
                 "object_id equal",
                 Q.id(42).build(),
@@ -111,6 +393,22 @@ mod tests {
                     value: 42.into(),
                 },
             ),
+            builder_test!(
+                "object_ids",
+                Q.ids(vec![1, 2]).build(),
+                PropIn {
+                    name: "object_id".to_string(),
+                    values: vec![1.into(), 2.into()],
+                },
+            ),
+            builder_test!(
+                "any of",
+                Q.any_of("name", ["a", "b"]).build(),
+                PropIn {
+                    name: "name".to_string(),
+                    values: vec!["a".into(), "b".into()],
+                },
+            ),
             builder_test!(
                 "simple word like",
                 Q.like("name", "phrase").build(),
@@ -119,6 +417,154 @@ mod tests {
                     pattern: "phrase".to_string(),
                 },
             ),
+            builder_test!(
+                "not like",
+                Q.not_like("name", "phrase").build(),
+                Not(Box::new(PropLike {
+                    name: "name".to_string(),
+                    pattern: "phrase".to_string(),
+                })),
+            ),
+            builder_test!(
+                "not any of",
+                Q.not_any_of("name", ["a", "b"]).build(),
+                Not(Box::new(PropIn {
+                    name: "name".to_string(),
+                    values: vec!["a".into(), "b".into()],
+                })),
+            ),
+            builder_test!(
+                "phrase",
+                Q.phrase("name", "long phrase").build(),
+                PropPhrase {
+                    name: "name".to_string(),
+                    phrase: "long phrase".to_string(),
+                },
+            ),
+            builder_test!(
+                "regex matches",
+                Q.matches("name", "^o.e$").unwrap().build(),
+                PropMatches {
+                    name: "name".to_string(),
+                    regex: "^o.e$".to_string(),
+                },
+            ),
+            builder_test!(
+                "greater than",
+                Q.greater_than("name", 42).build(),
+                PropCompare {
+                    name: "name".to_string(),
+                    comparison: Comparison::Greater,
+                    value: 42.into(),
+                },
+            ),
+            builder_test!(
+                "at most",
+                Q.at_most("name", 42).build(),
+                PropCompare {
+                    name: "name".to_string(),
+                    comparison: Comparison::LessOrEqual,
+                    value: 42.into(),
+                },
+            ),
+            builder_test!(
+                "between",
+                Q.between("name", 4, 8).build(),
+                PropBetween {
+                    name: "name".to_string(),
+                    low: 4.into(),
+                    high: 8.into(),
+                },
+            ),
+            builder_test!(
+                "starts with",
+                Q.starts_with("name", "phr").build(),
+                PropStartsWith {
+                    name: "name".to_string(),
+                    prefix: "phr".to_string(),
+                },
+            ),
+            builder_test!(
+                "missing",
+                Q.missing("name").build(),
+                Not(Box::new(PropExists {
+                    name: "name".to_string(),
+                })),
+            ),
+            builder_test!(
+                "missing all",
+                Q.missing_all(&["width", "height"]).build(),
+                And(vec![
+                    Not(Box::new(PropExists {
+                        name: "width".to_string(),
+                    })),
+                    Not(Box::new(PropExists {
+                        name: "height".to_string(),
+                    })),
+                ]),
+            ),
+            builder_test!(
+                "missing any",
+                Q.missing_any(&["width", "height"]).build(),
+                Or(vec![
+                    Not(Box::new(PropExists {
+                        name: "width".to_string(),
+                    })),
+                    Not(Box::new(PropExists {
+                        name: "height".to_string(),
+                    })),
+                ]),
+            ),
+            builder_test!(
+                "in subquery",
+                Q.in_subquery("group_id", Q.equal("kind", "group"), "object_id")
+                    .build(),
+                PropInQuery {
+                    name: "group_id".to_string(),
+                    query: Box::new(PropEqual {
+                        name: "kind".to_string(),
+                        value: "group".into(),
+                    }),
+                    field: "object_id".to_string(),
+                },
+            ),
+            builder_test!(
+                "grouped and",
+                Q.equal("kind", "shape")
+                    .and(|b| b.equal("width", 1).equal("height", 2))
+                    .build(),
+                And(vec![
+                    PropEqual {
+                        name: "kind".to_string(),
+                        value: "shape".into(),
+                    },
+                    And(vec![
+                        PropEqual {
+                            name: "width".to_string(),
+                            value: 1.into(),
+                        },
+                        PropEqual {
+                            name: "height".to_string(),
+                            value: 2.into(),
+                        },
+                    ]),
+                ]),
+            ),
+            builder_test!(
+                "grouped and with one criteria",
+                Q.and(|b| b.exists("width")).build(),
+                And(vec![PropExists {
+                    name: "width".to_string(),
+                }]),
+            ),
+            builder_test!(
+                "empty grouped and",
+                Q.equal("kind", "shape").and(|b| b).build(),
+                PropEqual {
+                    name: "kind".to_string(),
+                    value: "shape".into(),
+                },
+            ),
             builder_test!(
                 "anded queries",
                 Q.equal("name1", "value1")
@@ -146,4 +592,9 @@ mod tests {
             assert_eq!(expected_query, actual_query, "{}", description);
         }
     }
+
+    #[test]
+    fn invalid_regexes_are_rejected() {
+        assert!(Q.matches("name", "(unclosed").is_err());
+    }
 }